@@ -0,0 +1,174 @@
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite};
+use std::path::Path;
+use tokio::net::{TcpStream, ToSocketAddrs, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use crate::connection_bytes::Connection;
+use crate::frame::Frame;
+use crate::tls::{self, TlsConnector};
+use crate::Result;
+
+/// A handle to a connection to a Redis server.
+///
+/// The connection itself is owned by a manager task. `Client` only sends it commands over a channel, so a `Client` is
+/// cheap to clone and its clones can be used from many tasks at once. The commands of all clones are multiplexed over
+/// the one connection. The manager task exits once every clone has been dropped.
+///
+/// ```no_run
+/// # async fn run() -> my_redis::Result<()> {
+/// let client = my_redis::Client::connect("127.0.0.1:6379").await?;
+/// client.set("foo", "bar".into()).await?;
+/// assert_eq!(client.get("foo").await?, Some("bar".into()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Client {
+    tx: mpsc::Sender<Command>,
+}
+
+/// Multiple different commands are multiplexed over a single channel.
+enum Command {
+    Get {
+        key: String,
+        resp: Responder<Option<Bytes>>,
+    },
+    Set {
+        key: String,
+        value: Bytes,
+        resp: Responder<()>,
+    },
+}
+
+// Provide by the requester and used by the manager task to send the command back to the requester
+/// The `oneshot::Sender` is used to send the command response back to the requester.
+type Responder<T> = oneshot::Sender<Result<T>>;
+
+impl Client {
+    /// Connect to the server at `addr` and spawn the task that owns the connection
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Client> {
+        let socket = TcpStream::connect(addr).await?;
+        Ok(Client::spawn(Connection::new(socket)))
+    }
+
+    /// Connect to the server at `addr` over TLS, see `tls::connector`.
+    ///
+    /// `server_name` is the DNS name or IP address the server's certificate must be valid for.
+    pub async fn connect_tls(addr: impl ToSocketAddrs, server_name: &str, connector: &TlsConnector) -> Result<Client> {
+        let server_name = tls::server_name(server_name)?;
+        let socket = TcpStream::connect(addr).await?;
+        let stream = connector.connect(server_name, socket).await?;
+        Ok(Client::spawn(Connection::new(stream)))
+    }
+
+    /// Connect to the server listening on the Unix domain socket at `path`
+    pub async fn connect_unix(path: impl AsRef<Path>) -> Result<Client> {
+        let socket = UnixStream::connect(path).await?;
+        Ok(Client::spawn(Connection::new(socket)))
+    }
+
+    /// Spawn the manager task that owns `connection`
+    fn spawn<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(mut connection: Connection<T>) -> Client {
+        // Create a new channel with a capacity of at most 32.
+        let (tx, mut rx) = mpsc::channel(32);
+
+        // Spawn a manager task that receives commands and send to the server
+        tokio::spawn(async move {
+            while let Some(cmd) = rx.recv().await {
+                match cmd {
+                    Command::Get { key, resp } => {
+                        let res = get(&mut connection, &key).await;
+                        let _ = resp.send(res);
+                    }
+                    Command::Set { key, value, resp } => {
+                        let res = set(&mut connection, &key, value).await;
+                        let _ = resp.send(res);
+                    }
+                }
+            }
+        });
+        Client { tx }
+    }
+
+    /// Get the value of `key`, `None` if the key does not exist
+    pub async fn get(&self, key: &str) -> Result<Option<Bytes>> {
+        // Create a oneshot channel for the response
+        let (resp, resp_rx) = oneshot::channel();
+        self.send(Command::Get { key: key.to_string(), resp }).await?;
+        // Await the response
+        resp_rx.await?
+    }
+
+    /// Set `key` to `value`
+    pub async fn set(&self, key: &str, value: Bytes) -> Result<()> {
+        let (resp, resp_rx) = oneshot::channel();
+        self.send(Command::Set { key: key.to_string(), value, resp }).await?;
+        resp_rx.await?
+    }
+
+    async fn send(&self, cmd: Command) -> Result<()> {
+        self.tx.send(cmd).await.map_err(|_| "connection manager has shut down".into())
+    }
+}
+
+/// Send `GET key` and wait for the value
+async fn get<T: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<T>, key: &str) -> Result<Option<Bytes>> {
+    let frame = Frame::Array(vec![Frame::Bulk("GET".into()), Frame::Bulk(Bytes::copy_from_slice(key.as_bytes()))]);
+    connection.write_frame(&frame).await?;
+    match connection.read_frame().await? {
+        Some(Frame::Bulk(value)) => Ok(Some(value)),
+        Some(Frame::Null) => Ok(None),
+        Some(Frame::Error(msg)) => Err(msg.into()),
+        Some(frame) => Err(format!("unexpected frame: {}", frame).into()),
+        None => Err("connection reset by server".into()),
+    }
+}
+
+/// Send `SET key value` and wait for the acknowledgement
+async fn set<T: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<T>, key: &str, value: Bytes) -> Result<()> {
+    let frame = Frame::Array(vec![
+        Frame::Bulk("SET".into()),
+        Frame::Bulk(Bytes::copy_from_slice(key.as_bytes())),
+        Frame::Bulk(value),
+    ]);
+    connection.write_frame(&frame).await?;
+    match connection.read_frame().await? {
+        Some(Frame::Simple(response)) if response == "OK" => Ok(()),
+        Some(Frame::Error(msg)) => Err(msg.into()),
+        Some(frame) => Err(format!("unexpected frame: {}", frame).into()),
+        None => Err("connection reset by server".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    #[tokio::test]
+    async fn set_and_get() {
+        let server = Server::builder().addr("127.0.0.1:0").bind().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let client = Client::connect(addr).await.unwrap();
+        assert_eq!(client.get("foo").await.unwrap(), None);
+        client.set("foo", "bar".into()).await.unwrap();
+        assert_eq!(client.get("foo").await.unwrap(), Some("bar".into()));
+
+        // Commands from clones on other tasks share the connection, each gets its own reply
+        let tasks: Vec<_> = (0..16)
+            .map(|i| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    let key = format!("key{}", i);
+                    client.set(&key, Bytes::from(i.to_string())).await.unwrap();
+                    client.get(&key).await.unwrap()
+                })
+            })
+            .collect();
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.await.unwrap(), Some(Bytes::from(i.to_string())));
+        }
+    }
+}
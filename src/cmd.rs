@@ -0,0 +1,1098 @@
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bytes::{Bytes, BytesMut};
+use tokio::time::Instant;
+use crate::db::{Db, ExpireCondition, Expiry, SetCondition};
+use crate::frame::{Decimal, Frame};
+use crate::glob;
+
+/// The commands the server understands.
+///
+/// Clients send a command as an array of bulk strings, e.g. `*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n`. `from_frame` turns
+/// that array into a `Command`. Some Redis commands are parsed into the variant of a more general one, e.g. `SETEX` and
+/// `GETSET` into `Set`, and `INCR`, `DECR` and `DECRBY` into `Incr`.
+#[derive(Debug)]
+pub enum Command {
+    Get { key: String },
+    /// `SET key value [NX | XX] [GET] [EX seconds | PX milliseconds | EXAT unix-time-seconds |
+    /// PXAT unix-time-milliseconds | KEEPTTL]`. With `get` the reply is the previous value instead of `OK`.
+    Set {
+        key: String,
+        value: Bytes,
+        condition: SetCondition,
+        expiry: Expiry,
+        get: bool,
+    },
+    /// `SETNX key value`, which replies `1` or `0` where `SET NX` replies `OK` or nil
+    Setnx { key: String, value: Bytes },
+    /// `DEL key...`, or `UNLINK`
+    Del { keys: Vec<String> },
+    Exists { keys: Vec<String> },
+    /// `INCRBY key delta`
+    Incr { key: String, delta: i64 },
+    /// `INCRBYFLOAT key delta`
+    IncrFloat { key: String, delta: f64 },
+    Append { key: String, value: Bytes },
+    Strlen { key: String },
+    /// `GETRANGE key start end`, or its old name `SUBSTR`. Negative offsets count from the end of the value.
+    Getrange { key: String, start: i64, end: i64 },
+    Setrange { key: String, offset: usize, value: Bytes },
+    Mget { keys: Vec<String> },
+    /// `MSET`, or `MSETNX` when `condition` is `SetCondition::IfAbsent`
+    Mset {
+        pairs: Vec<(String, Bytes)>,
+        condition: SetCondition,
+    },
+    Getdel { key: String },
+    /// `GETEX key [EX seconds | PX milliseconds | EXAT unix-time-seconds | PXAT unix-time-milliseconds | PERSIST]`,
+    /// `PERSIST` being `Expiry::Never` and no option `Expiry::Keep`
+    Getex { key: String, expiry: Expiry },
+    /// `EXPIRE` and `PEXPIRE`, with the deadline already worked out. A deadline in the past deletes the key.
+    Expire {
+        key: String,
+        when: Instant,
+        condition: ExpireCondition,
+    },
+    /// `TTL`, or `PTTL` when `millis` is set
+    Ttl { key: String, millis: bool },
+    Persist { key: String },
+    Publish { channel: String, message: Bytes },
+    /// `SUBSCRIBE`, `PSUBSCRIBE` and their opposites change the state of the connection, so `server::process` runs them
+    /// rather than `apply`. An empty `UNSUBSCRIBE` leaves every channel, an empty `PUNSUBSCRIBE` every pattern.
+    Subscribe { channels: Vec<String> },
+    Unsubscribe { channels: Vec<String> },
+    Psubscribe { patterns: Vec<String> },
+    Punsubscribe { patterns: Vec<String> },
+    Ping { message: Option<Bytes> },
+    // Any command we do not implement, with the name as the client sent it
+    Unknown { name: String, args: Vec<Bytes> },
+}
+
+/// Why a frame could not be turned into a `Command`, or why the command failed.
+///
+/// Unlike a `frame::Error` the frame itself was fine, so the client gets an error reply and the connection stays open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The frame is not a non-empty array of strings, or an argument is not valid UTF-8 where text is expected
+    Protocol(String),
+    /// A known command with too few or too many arguments
+    WrongArity { name: String },
+    /// An option that is unknown, missing its value or clashes with another one
+    Syntax,
+    /// An argument that should be an integer is not one
+    NotAnInteger,
+    /// An expire time that is not positive, or too large to represent
+    InvalidExpireTime { name: String },
+    /// An option `EXPIRE` does not know
+    UnsupportedOption(String),
+    /// Options of `EXPIRE` that cannot be combined
+    IncompatibleOptions(&'static str),
+    /// An argument or a stored value that should be a float is not one
+    NotAFloat,
+    /// `INCR` and friends going past the range of a 64 bit integer
+    Overflow,
+    /// `DECRBY` by the one value whose negation does not fit
+    DecrementOverflow,
+    /// `INCRBYFLOAT` ending up with a value that cannot be stored
+    NanOrInfinity,
+    /// A negative `SETRANGE` offset
+    OffsetOutOfRange,
+    /// `APPEND` or `SETRANGE` growing a value past `MAX_STRING_LEN`
+    StringTooLong,
+    /// A `PSUBSCRIBE` pattern longer than `glob::MAX_PATTERN_LEN`
+    PatternTooLong,
+}
+
+/// The longest value `APPEND` and `SETRANGE` may build, Redis' default `proto-max-bulk-len`
+pub const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+impl Command {
+    /// Parse a command from a received frame.
+    ///
+    /// Unknown command names are not an error, they are returned as `Command::Unknown` so the caller can decide what
+    /// to reply.
+    pub fn from_frame(frame: Frame) -> Result<Command, Error> {
+        let mut parse = Parse::new(frame)?;
+
+        let command = match &parse.name[..] {
+            "get" => Command::Get {
+                key: parse.next_string()?,
+            },
+            "set" => parse_set(&mut parse)?,
+            "setnx" => Command::Setnx {
+                key: parse.next_string()?,
+                value: parse.next_bytes()?,
+            },
+            "setex" | "psetex" => {
+                let key = parse.next_string()?;
+                let option = if parse.name == "setex" { "EX" } else { "PX" };
+                let when = deadline(option, parse.next_int()?, &parse.name)?;
+                Command::Set {
+                    key,
+                    value: parse.next_bytes()?,
+                    condition: SetCondition::Always,
+                    expiry: Expiry::At(when),
+                    get: false,
+                }
+            }
+            "getset" => Command::Set {
+                key: parse.next_string()?,
+                value: parse.next_bytes()?,
+                condition: SetCondition::Always,
+                expiry: Expiry::Never,
+                get: true,
+            },
+            "del" | "unlink" => Command::Del {
+                keys: parse.one_or_more_strings()?,
+            },
+            "exists" => Command::Exists {
+                keys: parse.one_or_more_strings()?,
+            },
+            "incr" | "decr" => Command::Incr {
+                key: parse.next_string()?,
+                delta: if parse.name == "incr" { 1 } else { -1 },
+            },
+            "incrby" => Command::Incr {
+                key: parse.next_string()?,
+                delta: parse.next_int()?,
+            },
+            "decrby" => Command::Incr {
+                key: parse.next_string()?,
+                delta: parse.next_int()?.checked_neg().ok_or(Error::DecrementOverflow)?,
+            },
+            "incrbyfloat" => Command::IncrFloat {
+                key: parse.next_string()?,
+                delta: parse_float(&parse.next_bytes()?).ok_or(Error::NotAFloat)?,
+            },
+            "append" => Command::Append {
+                key: parse.next_string()?,
+                value: parse.next_bytes()?,
+            },
+            "strlen" => Command::Strlen {
+                key: parse.next_string()?,
+            },
+            "getrange" | "substr" => Command::Getrange {
+                key: parse.next_string()?,
+                start: parse.next_int()?,
+                end: parse.next_int()?,
+            },
+            "setrange" => Command::Setrange {
+                key: parse.next_string()?,
+                offset: usize::try_from(parse.next_int()?).map_err(|_| Error::OffsetOutOfRange)?,
+                value: parse.next_bytes()?,
+            },
+            "mget" => Command::Mget {
+                keys: parse.one_or_more_strings()?,
+            },
+            "mset" | "msetnx" => {
+                // At least one pair, and an odd number of arguments is a wrong arity
+                let mut pairs = vec![(parse.next_string()?, parse.next_bytes()?)];
+                while !parse.is_empty() {
+                    pairs.push((parse.next_string()?, parse.next_bytes()?));
+                }
+                let condition = if parse.name == "mset" { SetCondition::Always } else { SetCondition::IfAbsent };
+                Command::Mset { pairs, condition }
+            }
+            "getdel" => Command::Getdel {
+                key: parse.next_string()?,
+            },
+            "getex" => parse_getex(&mut parse)?,
+            "expire" => parse_expire(&mut parse, 1000)?,
+            "pexpire" => parse_expire(&mut parse, 1)?,
+            "ttl" => Command::Ttl {
+                key: parse.next_string()?,
+                millis: false,
+            },
+            "pttl" => Command::Ttl {
+                key: parse.next_string()?,
+                millis: true,
+            },
+            "persist" => Command::Persist {
+                key: parse.next_string()?,
+            },
+            "publish" => Command::Publish {
+                channel: parse.next_string()?,
+                message: parse.next_bytes()?,
+            },
+            // At least one channel or pattern
+            "subscribe" => Command::Subscribe {
+                channels: parse.one_or_more_strings()?,
+            },
+            "unsubscribe" => Command::Unsubscribe {
+                channels: parse.remaining_strings()?,
+            },
+            "psubscribe" => {
+                let patterns = parse.one_or_more_strings()?;
+                // Matching a long pattern against every published channel name gets expensive
+                if patterns.iter().any(|pattern| pattern.len() > glob::MAX_PATTERN_LEN) {
+                    return Err(Error::PatternTooLong);
+                }
+                Command::Psubscribe { patterns }
+            }
+            "punsubscribe" => Command::Punsubscribe {
+                patterns: parse.remaining_strings()?,
+            },
+            "ping" => Command::Ping {
+                message: match parse.is_empty() {
+                    true => None,
+                    false => Some(parse.next_bytes()?),
+                },
+            },
+            _ => {
+                let mut args = vec![];
+                while !parse.is_empty() {
+                    args.push(parse.next_bytes()?);
+                }
+                return Ok(Command::Unknown { name: parse.raw_name, args });
+            }
+        };
+
+        // Any arguments left over mean the frame was malformed
+        parse.finish()?;
+        Ok(command)
+    }
+
+    /// Run the command against `db` and return the reply.
+    ///
+    /// Subscription commands need a connection to work on, they are answered with an error here.
+    pub fn apply(self, db: &Db) -> Frame {
+        let bulk_or_null = |value: Option<Bytes>| value.map_or(Frame::Null, Frame::Bulk);
+        match self {
+            // `Frame::Bulk` expects data to be of type `Bytes`, a missing key is "Null" data
+            Command::Get { key } => bulk_or_null(db.get(&key)),
+            Command::Set { key, value, condition, expiry, get } => {
+                // The value is stored as `Bytes`
+                let (set, previous) = db.set_with(key, value, condition, expiry);
+                match (get, set) {
+                    (true, _) => bulk_or_null(previous),
+                    (false, true) => Frame::Simple("OK".to_string()),
+                    // `NX` or `XX` did not hold
+                    (false, false) => Frame::Null,
+                }
+            }
+            Command::Setnx { key, value } => {
+                let (set, _) = db.set_with(key, value, SetCondition::IfAbsent, Expiry::Never);
+                Frame::Integer(set as i64)
+            }
+            Command::Del { keys } => Frame::Integer(db.delete(&keys) as i64),
+            Command::Exists { keys } => Frame::Integer(db.exists(&keys) as i64),
+            Command::Incr { key, delta } => {
+                let result = db.update(&key, |value| {
+                    let current = match value.map(|value| parse_int(value)) {
+                        None => 0,
+                        Some(Some(n)) => n,
+                        Some(None) => return (None, Err(Error::NotAnInteger)),
+                    };
+                    match current.checked_add(delta) {
+                        Some(n) => (Some(Bytes::from(n.to_string())), Ok(n)),
+                        None => (None, Err(Error::Overflow)),
+                    }
+                });
+                result.map_or_else(error, Frame::Integer)
+            }
+            Command::IncrFloat { key, delta } => {
+                let result = db.update(&key, |value| {
+                    let current = match value.map(|value| parse_float(value)) {
+                        None => 0.0,
+                        Some(Some(n)) => n,
+                        Some(None) => return (None, Err(Error::NotAFloat)),
+                    };
+                    if !(current + delta).is_finite() {
+                        return (None, Err(Error::NanOrInfinity));
+                    }
+                    let value = Bytes::from(format_sum(current, delta));
+                    (Some(value.clone()), Ok(value))
+                });
+                result.map_or_else(error, Frame::Bulk)
+            }
+            Command::Append { key, value } => {
+                let result = db.update(&key, |current| {
+                    let current = current.map_or(&[][..], |current| &current[..]);
+                    let len = current.len() + value.len();
+                    if len > MAX_STRING_LEN {
+                        return (None, Err(Error::StringTooLong));
+                    }
+                    let mut appended = BytesMut::with_capacity(len);
+                    appended.extend_from_slice(current);
+                    appended.extend_from_slice(&value);
+                    (Some(appended.freeze()), Ok(len as i64))
+                });
+                result.map_or_else(error, Frame::Integer)
+            }
+            Command::Strlen { key } => Frame::Integer(db.get(&key).map_or(0, |value| value.len() as i64)),
+            Command::Getrange { key, start, end } => {
+                let value = db.get(&key).unwrap_or_default();
+                Frame::Bulk(match range(value.len(), start, end) {
+                    Some((start, end)) => value.slice(start..=end),
+                    None => Bytes::new(),
+                })
+            }
+            Command::Setrange { key, offset, value } => {
+                let result = db.update(&key, |current| {
+                    // Writing nothing neither changes nor creates the key
+                    if value.is_empty() {
+                        return (None, Ok(current.map_or(0, Bytes::len) as i64));
+                    }
+                    let current = current.map_or(&[][..], |current| &current[..]);
+                    let end = match offset.checked_add(value.len()) {
+                        Some(end) if end <= MAX_STRING_LEN => end,
+                        _ => return (None, Err(Error::StringTooLong)),
+                    };
+                    // Any gap between the current end and `offset` is filled with zero bytes
+                    let mut updated = BytesMut::from(current);
+                    if updated.len() < end {
+                        updated.resize(end, 0);
+                    }
+                    updated[offset..end].copy_from_slice(&value);
+                    let len = updated.len() as i64;
+                    (Some(updated.freeze()), Ok(len))
+                });
+                result.map_or_else(error, Frame::Integer)
+            }
+            Command::Mget { keys } => Frame::Array(db.get_many(&keys).into_iter().map(bulk_or_null).collect()),
+            Command::Mset { pairs, condition } => {
+                let set = db.set_many(pairs, condition);
+                match condition {
+                    SetCondition::Always => Frame::Simple("OK".to_string()),
+                    _ => Frame::Integer(set as i64),
+                }
+            }
+            Command::Getdel { key } => bulk_or_null(db.get_del(&key)),
+            Command::Getex { key, expiry } => bulk_or_null(db.get_ex(&key, expiry)),
+            Command::Expire { key, when, condition } => Frame::Integer(db.expire(&key, when, condition) as i64),
+            Command::Ttl { key, millis } => Frame::Integer(match db.ttl(&key) {
+                None => -2,
+                Some(None) => -1,
+                Some(Some(ttl)) if millis => ttl.as_millis() as i64,
+                // Rounded to the nearest second, like Redis
+                Some(Some(ttl)) => ((ttl.as_millis() + 500) / 1000) as i64,
+            }),
+            Command::Persist { key } => Frame::Integer(db.persist(&key) as i64),
+            Command::Publish { channel, message } => Frame::Integer(db.publish(&channel, message) as i64),
+            Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::Psubscribe { .. }
+            | Command::Punsubscribe { .. } => {
+                Frame::Error("ERR subscriptions need a connection, see server::process".to_string())
+            }
+            Command::Ping { message: None } => Frame::Simple("PONG".to_string()),
+            Command::Ping { message: Some(message) } => Frame::Bulk(message),
+            // Same reply as Redis
+            Command::Unknown { name, args } => {
+                let args: String = args.iter().map(|arg| format!("'{}' ", String::from_utf8_lossy(arg))).collect();
+                Frame::Error(format!("ERR unknown command '{}', with args beginning with: {}", name, args))
+            }
+        }
+    }
+}
+
+fn parse_set(parse: &mut Parse) -> Result<Command, Error> {
+    let key = parse.next_string()?;
+    let value = parse.next_bytes()?;
+    let mut condition = SetCondition::Always;
+    let mut expiry = None;
+    let mut get = false;
+
+    while !parse.is_empty() {
+        let option = parse.next_string()?.to_uppercase();
+        match &option[..] {
+            "NX" if condition == SetCondition::Always => condition = SetCondition::IfAbsent,
+            "XX" if condition == SetCondition::Always => condition = SetCondition::IfPresent,
+            "GET" => get = true,
+            "KEEPTTL" if expiry.is_none() => expiry = Some(Expiry::Keep),
+            "EX" | "PX" | "EXAT" | "PXAT" if expiry.is_none() => {
+                if parse.is_empty() {
+                    return Err(Error::Syntax);
+                }
+                expiry = Some(Expiry::At(deadline(&option, parse.next_int()?, &parse.name)?));
+            }
+            _ => return Err(Error::Syntax),
+        }
+    }
+
+    Ok(Command::Set {
+        key,
+        value,
+        condition,
+        expiry: expiry.unwrap_or(Expiry::Never),
+        get,
+    })
+}
+
+fn parse_getex(parse: &mut Parse) -> Result<Command, Error> {
+    let key = parse.next_string()?;
+    let mut expiry = None;
+    while !parse.is_empty() {
+        let option = parse.next_string()?.to_uppercase();
+        match &option[..] {
+            "PERSIST" if expiry.is_none() => expiry = Some(Expiry::Never),
+            "EX" | "PX" | "EXAT" | "PXAT" if expiry.is_none() => {
+                if parse.is_empty() {
+                    return Err(Error::Syntax);
+                }
+                expiry = Some(Expiry::At(deadline(&option, parse.next_int()?, &parse.name)?));
+            }
+            _ => return Err(Error::Syntax),
+        }
+    }
+    Ok(Command::Getex {
+        key,
+        expiry: expiry.unwrap_or(Expiry::Keep),
+    })
+}
+
+/// `EXPIRE key seconds [NX | XX | GT | LT]`, `unit` being the milliseconds per unit of the timeout
+fn parse_expire(parse: &mut Parse, unit: i64) -> Result<Command, Error> {
+    let key = parse.next_string()?;
+    let timeout = parse.next_int()?;
+    let mut condition = ExpireCondition::default();
+    while !parse.is_empty() {
+        match &parse.next_string()?.to_uppercase()[..] {
+            "NX" => condition.no_expiry = true,
+            "XX" => condition.has_expiry = true,
+            "GT" => condition.greater = true,
+            "LT" => condition.less = true,
+            option => return Err(Error::UnsupportedOption(option.to_string())),
+        }
+    }
+    if condition.no_expiry && (condition.has_expiry || condition.greater || condition.less) {
+        return Err(Error::IncompatibleOptions("NX and XX, GT or LT"));
+    }
+    if condition.greater && condition.less {
+        return Err(Error::IncompatibleOptions("GT and LT"));
+    }
+
+    let invalid = || Error::InvalidExpireTime { name: parse.name.clone() };
+    let millis = timeout.checked_mul(unit).ok_or_else(invalid)?;
+    // A timeout that is not positive deletes the key, which a deadline of now does
+    let when = match u64::try_from(millis) {
+        Ok(millis) => Instant::now().checked_add(Duration::from_millis(millis)).ok_or_else(invalid)?,
+        Err(_) => Instant::now(),
+    };
+    Ok(Command::Expire { key, when, condition })
+}
+
+/// The deadline set by an `EX`, `PX`, `EXAT` or `PXAT` option of command `name` with value `n`, which must be positive
+fn deadline(option: &str, n: i64, name: &str) -> Result<Instant, Error> {
+    let invalid = || Error::InvalidExpireTime { name: name.to_string() };
+    if n <= 0 {
+        return Err(invalid());
+    }
+    let millis = match option {
+        "EX" | "EXAT" => n.checked_mul(1000).ok_or_else(invalid)?,
+        _ => n,
+    };
+    let when = match option {
+        "EX" | "PX" => Instant::now().checked_add(Duration::from_millis(millis as u64)),
+        _ => unix_time(millis),
+    };
+    when.ok_or_else(invalid)
+}
+
+/// The `Instant` of a unix time in milliseconds. Times in the past map to now, so the key expires right away.
+fn unix_time(millis: i64) -> Option<Instant> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    match Duration::from_millis(millis as u64).checked_sub(now) {
+        Some(left) => Instant::now().checked_add(left),
+        None => Some(Instant::now()),
+    }
+}
+
+/// The inclusive byte range of a value of length `len` that `GETRANGE start end` returns, `None` if it is empty
+fn range(len: usize, start: i64, end: i64) -> Option<(usize, usize)> {
+    if start < 0 && end < 0 && start > end {
+        return None;
+    }
+    let len = len as i64;
+    // Negative offsets count from the end, and offsets past either end are clamped to it
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { (len + end).max(0) } else { end.min(len - 1) };
+    if start > end || len == 0 {
+        return None;
+    }
+    Some((start as usize, end as usize))
+}
+
+/// Parse an integer the way Redis does: no sign other than a leading `-`, no leading zeros and no whitespace
+fn parse_int(text: &[u8]) -> Option<i64> {
+    let digits = text.strip_prefix(b"-").unwrap_or(text);
+    let canonical = match digits {
+        [] => false,
+        [b'0'] => digits.len() == text.len(),
+        [b'0', ..] => false,
+        _ => digits.iter().all(u8::is_ascii_digit),
+    };
+    if !canonical {
+        return None;
+    }
+    std::str::from_utf8(text).ok()?.parse().ok()
+}
+
+/// Parse a float, as `INCRBYFLOAT` accepts it. `NaN` is not a number to add to.
+fn parse_float(text: &[u8]) -> Option<f64> {
+    let n: f64 = std::str::from_utf8(text).ok()?.parse().ok()?;
+    (!n.is_nan()).then_some(n)
+}
+
+/// `a + b` the way `INCRBYFLOAT` stores it, formatted like C's `%.17g`: rounded to 17 significant digits, with
+/// trailing zeros trimmed and an exponent for very large or very small sums.
+///
+/// Redis adds in `long double`, which is why `0.1` plus `0.2` is `0.3` there rather than `0.30000000000000004`. Adding
+/// the shortest decimal forms of both numbers exactly, then rounding, gives the same answers from `f64`s.
+fn format_sum(a: f64, b: f64) -> String {
+    const PRECISION: usize = 17;
+
+    let (a, b) = (Decimal::from(a), Decimal::from(b));
+    // Line both up on the smaller exponent, which turns them into integers that add up exactly
+    let mut exp = a.exp.min(b.exp);
+    let (a_digits, b_digits) = (a.shift_to(exp), b.shift_to(exp));
+    let (negative, mut digits) = if a.negative == b.negative {
+        (a.negative, add_digits(&a_digits, &b_digits))
+    } else if compare_digits(&a_digits, &b_digits).is_ge() {
+        (a.negative, sub_digits(&a_digits, &b_digits))
+    } else {
+        (b.negative, sub_digits(&b_digits, &a_digits))
+    };
+    digits.drain(..digits.iter().take_while(|&&d| d == 0).count());
+
+    // Round half to even, as `printf` does
+    if digits.len() > PRECISION {
+        let dropped = digits.split_off(PRECISION);
+        exp += dropped.len() as i32;
+        let round_up = match dropped[0].cmp(&5) {
+            std::cmp::Ordering::Equal if dropped[1..].iter().all(|&d| d == 0) => digits[PRECISION - 1] % 2 == 1,
+            order => order.is_ge(),
+        };
+        if round_up {
+            digits = add_digits(&digits, &[1]);
+            if digits.len() > PRECISION {
+                // 99...9 rounded up to 100...0
+                digits.pop();
+                exp += 1;
+            }
+        }
+    }
+    while digits.last() == Some(&0) {
+        digits.pop();
+        exp += 1;
+    }
+    if digits.is_empty() {
+        return "0".to_string();
+    }
+    Decimal { negative, digits, exp }.to_string()
+}
+
+/// The sum of two numbers given as decimal digits, most significant first
+fn add_digits(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut sum = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let (mut a, mut b) = (a.iter().rev(), b.iter().rev());
+    let mut carry = 0;
+    loop {
+        let (x, y) = (a.next(), b.next());
+        if x.is_none() && y.is_none() {
+            break;
+        }
+        let digit = x.unwrap_or(&0) + y.unwrap_or(&0) + carry;
+        sum.push(digit % 10);
+        carry = digit / 10;
+    }
+    if carry > 0 {
+        sum.push(carry);
+    }
+    sum.reverse();
+    sum
+}
+
+/// `a - b` for decimal digits where `a >= b`
+fn sub_digits(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut difference = Vec::with_capacity(a.len());
+    let mut b = b.iter().rev();
+    let mut borrow = 0;
+    for &x in a.iter().rev() {
+        let y = b.next().unwrap_or(&0) + borrow;
+        borrow = u8::from(x < y);
+        difference.push(x + 10 * borrow - y);
+    }
+    difference.reverse();
+    difference
+}
+
+/// Compare two numbers given as decimal digits
+fn compare_digits(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    let a = &a[a.iter().take_while(|&&d| d == 0).count()..];
+    let b = &b[b.iter().take_while(|&&d| d == 0).count()..];
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// The error reply for a command that failed
+fn error(err: Error) -> Frame {
+    Frame::Error(format!("ERR {}", err))
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Protocol(msg) => write!(fmt, "Protocol error: {}", msg),
+            Error::WrongArity { name } => write!(fmt, "wrong number of arguments for '{}' command", name),
+            Error::Syntax => "syntax error".fmt(fmt),
+            Error::NotAnInteger => "value is not an integer or out of range".fmt(fmt),
+            Error::InvalidExpireTime { name } => write!(fmt, "invalid expire time in '{}' command", name),
+            Error::UnsupportedOption(option) => write!(fmt, "Unsupported option {}", option),
+            Error::IncompatibleOptions(options) => write!(fmt, "{} options at the same time are not compatible", options),
+            Error::NotAFloat => "value is not a valid float".fmt(fmt),
+            Error::Overflow => "increment or decrement would overflow".fmt(fmt),
+            Error::DecrementOverflow => "decrement would overflow".fmt(fmt),
+            Error::NanOrInfinity => "increment would produce NaN or Infinity".fmt(fmt),
+            Error::OffsetOutOfRange => "offset is out of range".fmt(fmt),
+            Error::StringTooLong => "string exceeds maximum allowed size (proto-max-bulk-len)".fmt(fmt),
+            Error::PatternTooLong => write!(fmt, "pattern is longer than {} bytes", glob::MAX_PATTERN_LEN),
+        }
+    }
+}
+
+/// Walks the arguments of a command frame one at a time
+struct Parse {
+    // The command name, lowercased as command names are case insensitive
+    name: String,
+    // The command name as the client sent it
+    raw_name: String,
+    parts: std::vec::IntoIter<Frame>,
+}
+
+impl Parse {
+    fn new(frame: Frame) -> Result<Parse, Error> {
+        let mut parts = match frame {
+            Frame::Array(parts) => parts.into_iter(),
+            frame => return Err(Error::Protocol(format!("expected array, got {}", frame))),
+        };
+        let raw_name = match parts.next() {
+            Some(frame) => string(frame)?,
+            None => return Err(Error::Protocol("empty command".to_string())),
+        };
+        Ok(Parse {
+            name: raw_name.to_lowercase(),
+            raw_name,
+            parts,
+        })
+    }
+
+    /// Running out of arguments means the client sent too few of them
+    fn next(&mut self) -> Result<Frame, Error> {
+        self.parts.next().ok_or_else(|| Error::WrongArity { name: self.name.clone() })
+    }
+
+    /// The next argument as a string. Clients send bulk strings, but simple strings are accepted too.
+    fn next_string(&mut self) -> Result<String, Error> {
+        string(self.next()?)
+    }
+
+    /// The next argument as raw bytes. Bulk strings are passed through without copying.
+    fn next_bytes(&mut self) -> Result<Bytes, Error> {
+        match self.next()? {
+            Frame::Simple(s) => Ok(Bytes::from(s.into_bytes())),
+            Frame::Bulk(data) => Ok(data),
+            frame => Err(Error::Protocol(format!("expected simple or bulk string, got {}", frame))),
+        }
+    }
+
+    /// The next argument as a signed 64 bit integer, see `parse_int`
+    fn next_int(&mut self) -> Result<i64, Error> {
+        match self.next()? {
+            Frame::Integer(n) => Ok(n),
+            Frame::Simple(s) => parse_int(s.as_bytes()).ok_or(Error::NotAnInteger),
+            Frame::Bulk(data) => parse_int(&data).ok_or(Error::NotAnInteger),
+            frame => Err(Error::Protocol(format!("expected simple or bulk string, got {}", frame))),
+        }
+    }
+
+    /// All the remaining arguments as strings
+    fn remaining_strings(&mut self) -> Result<Vec<String>, Error> {
+        let mut strings = vec![];
+        while !self.is_empty() {
+            strings.push(self.next_string()?);
+        }
+        Ok(strings)
+    }
+
+    /// All the remaining arguments as strings, of which there must be at least one
+    fn one_or_more_strings(&mut self) -> Result<Vec<String>, Error> {
+        let first = self.next_string()?;
+        let mut strings = self.remaining_strings()?;
+        strings.insert(0, first);
+        Ok(strings)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.parts.len() == 0
+    }
+
+    /// Ensure there are no more arguments
+    fn finish(&mut self) -> Result<(), Error> {
+        match self.parts.next() {
+            None => Ok(()),
+            Some(_) => Err(Error::WrongArity { name: self.name.clone() }),
+        }
+    }
+}
+
+fn string(frame: Frame) -> Result<String, Error> {
+    match frame {
+        Frame::Simple(s) => Ok(s),
+        Frame::Bulk(data) => String::from_utf8(data.to_vec()).map_err(|_| Error::Protocol("invalid string".to_string())),
+        frame => Err(Error::Protocol(format!("expected simple or bulk string, got {}", frame))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time;
+
+    /// Run the command made of `args` against `db`, replying to a malformed one the way `server::process` does
+    fn run(db: &Db, args: &[&str]) -> Frame {
+        let frame = Frame::Array(args.iter().map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes()))).collect());
+        match Command::from_frame(frame) {
+            Ok(command) => command.apply(db),
+            Err(err) => error(err),
+        }
+    }
+
+    fn ok() -> Frame {
+        Frame::Simple("OK".to_string())
+    }
+
+    fn bulk(value: &str) -> Frame {
+        Frame::Bulk(Bytes::copy_from_slice(value.as_bytes()))
+    }
+
+    fn err(msg: &str) -> Frame {
+        Frame::Error(msg.to_string())
+    }
+
+    /// Milliseconds since the unix epoch, as `EXAT` and `PXAT` count them
+    fn unix_millis() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_with_expiry() {
+        let db = Db::new();
+        assert_eq!(run(&db, &["SET", "ex", "v", "EX", "10"]), ok());
+        assert_eq!(run(&db, &["PTTL", "ex"]), Frame::Integer(10_000));
+        assert_eq!(run(&db, &["SET", "px", "v", "px", "1500"]), ok());
+        assert_eq!(run(&db, &["PTTL", "px"]), Frame::Integer(1500));
+        assert_eq!(run(&db, &["SETEX", "setex", "5", "v"]), ok());
+        assert_eq!(run(&db, &["TTL", "setex"]), Frame::Integer(5));
+
+        // `EXAT` and `PXAT` go by the wall clock, which keeps running while the runtime's clock is paused
+        let exat = (unix_millis() / 1000 + 100).to_string();
+        assert_eq!(run(&db, &["SET", "exat", "v", "EXAT", &exat]), ok());
+        assert!(matches!(run(&db, &["TTL", "exat"]), Frame::Integer(99..=100)));
+        let pxat = (unix_millis() + 5000).to_string();
+        assert_eq!(run(&db, &["SET", "pxat", "v", "PXAT", &pxat]), ok());
+        assert!(matches!(run(&db, &["PTTL", "pxat"]), Frame::Integer(4000..=5000)));
+        // A time in the past expires the key right away
+        assert_eq!(run(&db, &["SET", "past", "v", "PXAT", "1"]), ok());
+        assert_eq!(run(&db, &["GET", "past"]), Frame::Null);
+
+        time::advance(Duration::from_millis(1500)).await;
+        assert_eq!(run(&db, &["GET", "px"]), Frame::Null);
+        assert_eq!(run(&db, &["GET", "ex"]), bulk("v"));
+
+        assert_eq!(run(&db, &["SET", "k", "v", "EX", "0"]), err("ERR invalid expire time in 'set' command"));
+        assert_eq!(run(&db, &["SET", "k", "v", "PX", "-1"]), err("ERR invalid expire time in 'set' command"));
+        assert_eq!(run(&db, &["SET", "k", "v", "EX", "1", "PX", "1"]), err("ERR syntax error"));
+        assert_eq!(run(&db, &["SET", "k", "v", "EX"]), err("ERR syntax error"));
+        assert_eq!(run(&db, &["SET", "k", "v", "EX", "ten"]), err("ERR value is not an integer or out of range"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_keepttl() {
+        let db = Db::new();
+        assert_eq!(run(&db, &["SET", "k", "a", "EX", "100"]), ok());
+        assert_eq!(run(&db, &["SET", "k", "b", "KEEPTTL"]), ok());
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(100));
+        assert_eq!(run(&db, &["GET", "k"]), bulk("b"));
+        // Without `KEEPTTL` a `SET` drops the deadline
+        assert_eq!(run(&db, &["SET", "k", "c"]), ok());
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(-1));
+        assert_eq!(run(&db, &["SET", "k", "c", "KEEPTTL", "EX", "1"]), err("ERR syntax error"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn set_conditions_and_get() {
+        let db = Db::new();
+        assert_eq!(run(&db, &["SET", "k", "a", "XX"]), Frame::Null);
+        assert_eq!(run(&db, &["GET", "k"]), Frame::Null);
+        assert_eq!(run(&db, &["SET", "k", "a", "NX"]), ok());
+        assert_eq!(run(&db, &["SET", "k", "b", "NX"]), Frame::Null);
+        assert_eq!(run(&db, &["SET", "k", "b", "xx"]), ok());
+        assert_eq!(run(&db, &["GET", "k"]), bulk("b"));
+        assert_eq!(run(&db, &["SET", "k", "c", "NX", "XX"]), err("ERR syntax error"));
+
+        // `GET` replies with the previous value, whether or not the condition let the new one in
+        assert_eq!(run(&db, &["SET", "k", "c", "GET"]), bulk("b"));
+        assert_eq!(run(&db, &["SET", "k", "d", "NX", "GET"]), bulk("c"));
+        assert_eq!(run(&db, &["GET", "k"]), bulk("c"));
+        assert_eq!(run(&db, &["SET", "new", "v", "GET"]), Frame::Null);
+        assert_eq!(run(&db, &["GETSET", "new", "w"]), bulk("v"));
+
+        // An expired key counts as absent
+        assert_eq!(run(&db, &["SET", "short", "a", "PX", "100"]), ok());
+        time::advance(Duration::from_millis(100)).await;
+        assert_eq!(run(&db, &["SET", "short", "b", "XX"]), Frame::Null);
+        assert_eq!(run(&db, &["SET", "short", "b", "NX", "GET"]), Frame::Null);
+        assert_eq!(run(&db, &["GET", "short"]), bulk("b"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expire_conditions() {
+        let db = Db::new();
+        assert_eq!(run(&db, &["EXPIRE", "missing", "10"]), Frame::Integer(0));
+        run(&db, &["SET", "k", "v"]);
+        // Without a deadline a key lives forever: later than any deadline for `GT`, and `XX` does not hold
+        assert_eq!(run(&db, &["EXPIRE", "k", "100", "XX"]), Frame::Integer(0));
+        assert_eq!(run(&db, &["EXPIRE", "k", "100", "GT"]), Frame::Integer(0));
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(-1));
+        assert_eq!(run(&db, &["EXPIRE", "k", "100", "LT"]), Frame::Integer(1));
+        assert_eq!(run(&db, &["EXPIRE", "k", "50", "NX"]), Frame::Integer(0));
+        assert_eq!(run(&db, &["EXPIRE", "k", "200", "GT"]), Frame::Integer(1));
+        assert_eq!(run(&db, &["EXPIRE", "k", "150", "GT"]), Frame::Integer(0));
+        assert_eq!(run(&db, &["EXPIRE", "k", "300", "LT"]), Frame::Integer(0));
+        assert_eq!(run(&db, &["EXPIRE", "k", "150", "lt", "XX"]), Frame::Integer(1));
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(150));
+        assert_eq!(run(&db, &["PEXPIRE", "k", "2500"]), Frame::Integer(1));
+        assert_eq!(run(&db, &["PTTL", "k"]), Frame::Integer(2500));
+
+        assert_eq!(
+            run(&db, &["EXPIRE", "k", "10", "NX", "XX"]),
+            err("ERR NX and XX, GT or LT options at the same time are not compatible")
+        );
+        assert_eq!(
+            run(&db, &["EXPIRE", "k", "10", "GT", "LT"]),
+            err("ERR GT and LT options at the same time are not compatible")
+        );
+        assert_eq!(run(&db, &["EXPIRE", "k", "10", "YY"]), err("ERR Unsupported option YY"));
+        assert_eq!(
+            run(&db, &["EXPIRE", "k", &i64::MAX.to_string()]),
+            err("ERR invalid expire time in 'expire' command")
+        );
+
+        // A deadline that is not in the future deletes the key
+        assert_eq!(run(&db, &["EXPIRE", "k", "0"]), Frame::Integer(1));
+        assert_eq!(run(&db, &["EXISTS", "k"]), Frame::Integer(0));
+        run(&db, &["SET", "k", "v"]);
+        assert_eq!(run(&db, &["PEXPIRE", "k", "-5"]), Frame::Integer(1));
+        assert_eq!(run(&db, &["GET", "k"]), Frame::Null);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ttl_and_persist() {
+        let db = Db::new();
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(-2));
+        assert_eq!(run(&db, &["PTTL", "k"]), Frame::Integer(-2));
+        run(&db, &["SET", "k", "v"]);
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(-1));
+        assert_eq!(run(&db, &["PTTL", "k"]), Frame::Integer(-1));
+        assert_eq!(run(&db, &["PERSIST", "k"]), Frame::Integer(0));
+
+        // `TTL` rounds to the nearest second
+        run(&db, &["PEXPIRE", "k", "1499"]);
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(1));
+        run(&db, &["PEXPIRE", "k", "1500"]);
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(2));
+        time::advance(Duration::from_millis(1100)).await;
+        assert_eq!(run(&db, &["PTTL", "k"]), Frame::Integer(400));
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(0));
+
+        assert_eq!(run(&db, &["PERSIST", "k"]), Frame::Integer(1));
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(-1));
+        time::advance(Duration::from_secs(10)).await;
+        assert_eq!(run(&db, &["GET", "k"]), bulk("v"));
+
+        run(&db, &["PEXPIRE", "k", "10"]);
+        time::advance(Duration::from_millis(10)).await;
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(-2));
+        assert_eq!(run(&db, &["PERSIST", "k"]), Frame::Integer(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn getex() {
+        let db = Db::new();
+        assert_eq!(run(&db, &["GETEX", "k", "EX", "10"]), Frame::Null);
+        run(&db, &["SET", "k", "v"]);
+        assert_eq!(run(&db, &["GETEX", "k", "EX", "10"]), bulk("v"));
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(10));
+        // No option leaves the deadline alone
+        assert_eq!(run(&db, &["GETEX", "k"]), bulk("v"));
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(10));
+        assert_eq!(run(&db, &["GETEX", "k", "PERSIST"]), bulk("v"));
+        assert_eq!(run(&db, &["TTL", "k"]), Frame::Integer(-1));
+        assert_eq!(run(&db, &["GETEX", "k", "PX", "100"]), bulk("v"));
+        assert_eq!(run(&db, &["PTTL", "k"]), Frame::Integer(100));
+        // A time in the past still returns the value, then deletes the key
+        assert_eq!(run(&db, &["GETEX", "k", "EXAT", "1"]), bulk("v"));
+        assert_eq!(run(&db, &["EXISTS", "k"]), Frame::Integer(0));
+        assert_eq!(run(&db, &["GETEX", "k", "EX", "1", "PERSIST"]), err("ERR syntax error"));
+        assert_eq!(run(&db, &["GETEX", "k", "EX", "0"]), err("ERR invalid expire time in 'getex' command"));
+    }
+
+    /// Run each command of `table` in turn against a fresh `Db`, checking its reply
+    fn check(table: &[(&[&str], Frame)]) {
+        let db = Db::new();
+        for (args, expected) in table {
+            assert_eq!(&run(&db, args), expected, "{:?}", args);
+        }
+    }
+
+    #[tokio::test]
+    async fn incr_and_decr() {
+        let (max, min) = (i64::MAX.to_string(), i64::MIN.to_string());
+        check(&[
+            (&["INCR", "n"], Frame::Integer(1)),
+            (&["INCRBY", "n", "41"], Frame::Integer(42)),
+            (&["DECR", "n"], Frame::Integer(41)),
+            (&["DECRBY", "n", "-9"], Frame::Integer(50)),
+            (&["INCRBY", "n", "-100"], Frame::Integer(-50)),
+            (&["GET", "n"], bulk("-50")),
+            // Only canonical integers count as integers
+            (&["SET", "s", "abc"], ok()),
+            (&["INCR", "s"], err("ERR value is not an integer or out of range")),
+            (&["SET", "s", " 1"], ok()),
+            (&["INCR", "s"], err("ERR value is not an integer or out of range")),
+            (&["SET", "s", "01"], ok()),
+            (&["DECR", "s"], err("ERR value is not an integer or out of range")),
+            (&["SET", "s", "1.5"], ok()),
+            (&["INCRBY", "s", "1"], err("ERR value is not an integer or out of range")),
+            (&["GET", "s"], bulk("1.5")),
+            (&["INCRBY", "n", "x"], err("ERR value is not an integer or out of range")),
+            (&["INCRBY", "n", "9223372036854775808"], err("ERR value is not an integer or out of range")),
+            // At the edges of a 64 bit integer
+            (&["SET", "max", &max], ok()),
+            (&["INCR", "max"], err("ERR increment or decrement would overflow")),
+            (&["INCRBY", "max", "-1"], Frame::Integer(i64::MAX - 1)),
+            (&["INCRBY", "max", "2"], err("ERR increment or decrement would overflow")),
+            (&["SET", "min", &min], ok()),
+            (&["DECR", "min"], err("ERR increment or decrement would overflow")),
+            (&["DECRBY", "min", "1"], err("ERR increment or decrement would overflow")),
+            (&["INCRBY", "min", &max], Frame::Integer(-1)),
+            (&["DECRBY", "n", &min], err("ERR decrement would overflow")),
+            (&["GET", "min"], bulk("-1")),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn getrange_and_setrange() {
+        check(&[
+            (&["SET", "k", "Hello World"], ok()),
+            (&["GETRANGE", "k", "0", "4"], bulk("Hello")),
+            (&["GETRANGE", "k", "-5", "-1"], bulk("World")),
+            (&["GETRANGE", "k", "0", "-1"], bulk("Hello World")),
+            (&["GETRANGE", "k", "-100", "2"], bulk("Hel")),
+            (&["GETRANGE", "k", "6", "100"], bulk("World")),
+            (&["GETRANGE", "k", "-1", "-5"], bulk("")),
+            (&["GETRANGE", "k", "5", "3"], bulk("")),
+            (&["GETRANGE", "k", "100", "200"], bulk("")),
+            (&["SUBSTR", "k", "-3", "-2"], bulk("rl")),
+            (&["GETRANGE", "missing", "0", "-1"], bulk("")),
+            // Past the end of the value the gap is filled with zero bytes
+            (&["SETRANGE", "k", "6", "Redis"], Frame::Integer(11)),
+            (&["GET", "k"], bulk("Hello Redis")),
+            (&["SETRANGE", "new", "3", "ab"], Frame::Integer(5)),
+            (&["GET", "new"], bulk("\0\0\0ab")),
+            (&["SETRANGE", "k", "13", "!"], Frame::Integer(14)),
+            (&["GET", "k"], bulk("Hello Redis\0\0!")),
+            // Writing nothing does not create the key
+            (&["SETRANGE", "empty", "10", ""], Frame::Integer(0)),
+            (&["EXISTS", "empty"], Frame::Integer(0)),
+            (&["SETRANGE", "k", "-1", "x"], err("ERR offset is out of range")),
+            (
+                &["SETRANGE", "k", &MAX_STRING_LEN.to_string(), "x"],
+                err("ERR string exceeds maximum allowed size (proto-max-bulk-len)"),
+            ),
+            (
+                &["SETRANGE", "k", &(MAX_STRING_LEN - 1).to_string(), "xy"],
+                err("ERR string exceeds maximum allowed size (proto-max-bulk-len)"),
+            ),
+            (&["STRLEN", "k"], Frame::Integer(14)),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn multiple_keys() {
+        let array = |values: &[Option<&str>]| {
+            Frame::Array(values.iter().map(|value| value.map_or(Frame::Null, bulk)).collect())
+        };
+        check(&[
+            (&["MSET", "a", "1", "b", "2"], ok()),
+            (&["MGET", "a", "b", "c"], array(&[Some("1"), Some("2"), None])),
+            (&["MSET", "a", "1", "b"], err("ERR wrong number of arguments for 'mset' command")),
+            // `MSETNX` sets every key or none of them
+            (&["MSETNX", "b", "3", "c", "3"], Frame::Integer(0)),
+            (&["MGET", "b", "c"], array(&[Some("2"), None])),
+            (&["MSETNX", "c", "3", "d", "4"], Frame::Integer(1)),
+            (&["MGET", "c", "d"], array(&[Some("3"), Some("4")])),
+            (&["EXISTS", "a", "a", "x"], Frame::Integer(2)),
+            (&["DEL", "a", "b", "x"], Frame::Integer(2)),
+            (&["UNLINK", "c"], Frame::Integer(1)),
+            (&["MGET", "a", "b", "c", "d"], array(&[None, None, None, Some("4")])),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn getdel_getex_and_append() {
+        check(&[
+            (&["SET", "k", "v", "EX", "100"], ok()),
+            (&["GETDEL", "k"], bulk("v")),
+            (&["EXISTS", "k"], Frame::Integer(0)),
+            (&["GETDEL", "k"], Frame::Null),
+            (&["APPEND", "k", "ab"], Frame::Integer(2)),
+            (&["APPEND", "k", "cd"], Frame::Integer(4)),
+            (&["GETEX", "k", "EX", "10"], bulk("abcd")),
+            (&["TTL", "k"], Frame::Integer(10)),
+            (&["GETEX", "k", "PERSIST"], bulk("abcd")),
+            (&["TTL", "k"], Frame::Integer(-1)),
+            (&["GETEX", "missing", "PERSIST"], Frame::Null),
+            (&["SETNX", "k", "x"], Frame::Integer(0)),
+            (&["SETNX", "n", "x"], Frame::Integer(1)),
+            (&["GETDEL", "k", "n"], err("ERR wrong number of arguments for 'getdel' command")),
+        ]);
+    }
+
+    #[test]
+    fn float_sums_like_redis() {
+        assert_eq!(format_sum(0.1, 0.2), "0.3");
+        assert_eq!(format_sum(0.1, 0.7), "0.8");
+        assert_eq!(format_sum(10.5, 0.1), "10.6");
+        assert_eq!(format_sum(5.0e3, 2.0e2), "5200");
+        assert_eq!(format_sum(0.0, 3.0), "3");
+        assert_eq!(format_sum(1.5, -1.5), "0");
+        assert_eq!(format_sum(0.1, -0.3), "-0.2");
+        assert_eq!(format_sum(-0.1, 0.0), "-0.1");
+        assert_eq!(format_sum(0.0001, 0.0), "0.0001");
+    }
+
+    #[test]
+    fn float_sums_round_to_17_digits() {
+        assert_eq!(format_sum(1.0, 1e-17), "1");
+        assert_eq!(format_sum(1.0, 1e-16), "1.0000000000000001");
+        assert_eq!(format_sum(0.9999999999999999, 1e-16), "1");
+        // Exactly half way, rounded to even
+        assert_eq!(format_sum(1.0, 1.5e-16), "1.0000000000000002");
+        assert_eq!(format_sum(1.0, 2.5e-16), "1.0000000000000002");
+        assert_eq!(format_sum(12345678901234567.0, 0.5), "12345678901234568");
+    }
+
+    #[test]
+    fn float_sums_with_exponents() {
+        assert_eq!(format_sum(1e20, 0.0), "1e+20");
+        assert_eq!(format_sum(1.5e17, 0.0), "1.5e+17");
+        assert_eq!(format_sum(1e16, 0.0), "10000000000000000");
+        assert_eq!(format_sum(1e-5, 0.0), "1e-05");
+        assert_eq!(format_sum(-2.5e-10, 0.0), "-2.5e-10");
+        assert_eq!(format_sum(1e300, 1e-300), "1e+300");
+        assert_eq!(format_sum(f64::MAX, 0.0), "1.7976931348623157e+308");
+    }
+}
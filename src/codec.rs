@@ -0,0 +1,144 @@
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+use crate::connection_bytes::{decode, encode};
+use crate::frame::{Frame, Limits, Protocol};
+
+/// A `Decoder`/`Encoder` pair for RESP frames.
+///
+/// Wrapping a transport in `tokio_util::codec::Framed::new(stream, RespCodec::new())` turns it into a
+/// `Stream<Item = Result<Frame>>` and a `Sink<Frame>`, so `StreamExt`/`SinkExt` combinators can be used instead of a
+/// hand-written read loop. The parsing and encoding are the same ones `Connection` uses.
+#[derive(Debug, Default)]
+pub struct RespCodec {
+    // Decides how RESP3-only frames are encoded, see `Connection::protocol`
+    protocol: Protocol,
+    // Caps on incoming frames, see `Limits`
+    limits: Limits,
+}
+
+impl RespCodec {
+    pub fn new() -> RespCodec {
+        RespCodec::default()
+    }
+
+    /// Create a codec that rejects incoming frames breaking `limits`
+    pub fn with_limits(limits: Limits) -> RespCodec {
+        RespCodec { limits, ..RespCodec::default() }
+    }
+
+    /// The protocol version frames are encoded as
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Switch the protocol version, e.g. after a `HELLO 3`. Reach the codec through `Framed::codec_mut`.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = Frame;
+    type Error = crate::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Self::Error> {
+        Ok(decode(src, &self.limits)?)
+    }
+}
+
+impl Encoder<Frame> for RespCodec {
+    type Error = crate::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        encode(&frame, self.protocol, dst);
+        Ok(())
+    }
+}
+
+impl Encoder<&Frame> for RespCodec {
+    type Error = crate::Error;
+
+    fn encode(&mut self, frame: &Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        encode(frame, self.protocol, dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    #[tokio::test]
+    async fn roundtrip_over_framed() {
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut a, mut b) = (Framed::new(a, RespCodec::new()), Framed::new(b, RespCodec::new()));
+        a.codec_mut().set_protocol(Protocol::Resp3);
+        let bulk = |s: &'static str| Frame::Bulk(Bytes::from(s));
+        let frames = [
+            Frame::Array(vec![bulk("SET"), bulk("k"), bulk("")]),
+            Frame::Map(vec![(Frame::Simple("a".to_string()), Frame::Integer(-1)), (bulk("b"), Frame::Null)]),
+            Frame::Set(vec![Frame::Boolean(true), Frame::Boolean(false)]),
+            Frame::Double(2.5),
+            Frame::BigNumber("-123456789012345678901234567890".to_string()),
+            Frame::Verbatim { format: "txt".to_string(), data: Bytes::from("some\r\ntext") },
+            Frame::Attribute {
+                attributes: vec![(bulk("ttl"), Frame::Integer(100))],
+                data: Box::new(Frame::Push(vec![bulk("message"), bulk("ch"), bulk("hi")])),
+            },
+            Frame::Error("ERR oops".to_string()),
+        ];
+        for frame in &frames {
+            a.feed(frame).await.unwrap();
+        }
+        SinkExt::<&Frame>::flush(&mut a).await.unwrap();
+        for frame in frames {
+            assert_eq!(b.next().await.unwrap().unwrap(), frame);
+        }
+
+        // The same frames sent as RESP2 arrive in their RESP2 shapes
+        a.codec_mut().set_protocol(Protocol::Resp2);
+        a.send(Frame::Map(vec![(bulk("a"), Frame::Boolean(true))])).await.unwrap();
+        assert_eq!(b.next().await.unwrap().unwrap(), Frame::Array(vec![bulk("a"), Frame::Integer(1)]));
+
+        // Closing one end ends the other's stream
+        drop(a);
+        assert!(b.next().await.is_none());
+    }
+
+    #[test]
+    fn partial_frame() {
+        let mut codec = RespCodec::new();
+        let frame = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n+OK\r\n";
+        // Every strict prefix of the array leaves the buffer as it is
+        for len in 0..frame.len() - 5 {
+            let mut src = BytesMut::from(&frame[..len]);
+            assert_eq!(codec.decode(&mut src).unwrap(), None, "{} bytes", len);
+            assert_eq!(&src[..], &frame[..len]);
+        }
+        let mut src = BytesMut::from(&frame[..]);
+        let get = Frame::Array(vec![Frame::Bulk("GET".into()), Frame::Bulk("foo".into())]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(get));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(Frame::Simple("OK".to_string())));
+        assert!(src.is_empty());
+    }
+
+    #[tokio::test]
+    async fn frames_over_the_limits() {
+        let limits = Limits { max_bulk_len: 4, max_depth: 1, ..Limits::default() };
+        let mut codec = RespCodec::with_limits(limits);
+        let err = codec.decode(&mut BytesMut::from(&b"$5\r\n"[..])).unwrap_err();
+        assert_eq!(err.to_string(), "Protocol error: invalid length at offset 1");
+        let err = codec.decode(&mut BytesMut::from(&b"*1\r\n*1\r\n*1\r\n"[..])).unwrap_err();
+        assert_eq!(err.to_string(), "Protocol error: aggregates nested too deeply at offset 8");
+
+        // Through `Framed` the error ends the stream
+        let (mut a, b) = tokio::io::duplex(1024);
+        let mut b = Framed::new(b, RespCodec::with_limits(limits));
+        tokio::io::AsyncWriteExt::write_all(&mut a, b"$4\r\nabcd\r\n$5\r\nabcde\r\n").await.unwrap();
+        assert_eq!(b.next().await.unwrap().unwrap(), Frame::Bulk("abcd".into()));
+        assert!(b.next().await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,404 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tracing::Level;
+use crate::connection::{Buffering, Timeouts};
+use crate::db;
+use crate::frame::{self, Limits};
+use crate::server::{Builder, Server};
+use crate::tls;
+
+/// Server settings, read from a `redis.conf` style file and overridden from the command line.
+///
+/// The file holds one directive per line, e.g. `port 6380`, with `#` comments. Directives and their arguments are the
+/// same as Redis' where Redis has one. On the command line, `--port 6380` sets the same thing as the `port 6380` line.
+///
+/// | Directive | Default | |
+/// |---|---|---|
+/// | `bind` | `127.0.0.1` | Address to listen on |
+/// | `port` | `6379` | TCP port, `0` turns TCP off |
+/// | `unixsocket`, `unixsocketperm` | none | Unix domain socket to listen on, and its permissions in octal |
+/// | `timeout` | `0` | Seconds a client may stay idle, `0` for no limit |
+/// | `stall-timeout` | `30` | Seconds a client may take to finish sending a frame, `0` for no limit |
+/// | `maxclients` | `10000` | Clients served at once, lowered to fit the open files limit |
+/// | `shutdown-timeout` | `10` | Seconds to wait for clients when shutting down |
+/// | `shards` | `16` | Number of `Db` shards up to 1024, more means less lock contention between clients |
+/// | `client-query-buffer-limit` | `1gb` | See `Limits::max_buffer` |
+/// | `proto-max-bulk-len` | `512mb` | See `Limits::max_bulk_len` |
+/// | `proto-max-array-len`, `proto-max-depth`, `proto-max-inline-len` | | See `Limits` |
+/// | `buffering` | `bytes` | `bytes` or `vec`, see `Buffering` |
+/// | `tls-cert-file`, `tls-key-file`, `tls-ca-cert-file` | none | TLS for the TCP port, see `tls::acceptor` |
+/// | `loglevel` | `notice` | `debug`, `verbose`, `notice` or `warning` |
+///
+/// Sizes take Redis' units: `k`, `m` and `g` are powers of 1000, `kb`, `mb` and `gb` powers of 1024.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub bind: String,
+    pub port: u16,
+    pub unix_socket: Option<PathBuf>,
+    pub unix_socket_perm: Option<u32>,
+    pub timeouts: Timeouts,
+    pub max_clients: usize,
+    pub shutdown_timeout: Duration,
+    pub shards: usize,
+    pub limits: Limits,
+    pub buffering: Buffering,
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    pub tls_ca_cert_file: Option<PathBuf>,
+    pub log_level: Level,
+}
+
+/// Why a configuration was rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A bad line in a config file
+    File { path: PathBuf, line: usize, message: String },
+    /// A bad command line argument
+    Arg { message: String },
+    /// Settings that are fine on their own but not together
+    Invalid { message: String },
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: "127.0.0.1".to_string(),
+            port: 6379,
+            unix_socket: None,
+            unix_socket_perm: None,
+            timeouts: Timeouts {
+                idle: None,
+                stall: Some(Duration::from_secs(30)),
+            },
+            max_clients: 10000,
+            shutdown_timeout: Duration::from_secs(10),
+            shards: db::DEFAULT_SHARDS,
+            limits: Limits::default(),
+            buffering: Buffering::default(),
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            log_level: Level::INFO,
+        }
+    }
+}
+
+impl Config {
+    /// Build the configuration from command line arguments, without the program name: an optional config file,
+    /// followed by `--directive value...` overrides.
+    ///
+    /// The result has been validated.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        let mut args = args.into_iter().peekable();
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            config.load(Path::new(&path))?;
+        }
+
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                return Err(ConfigError::Arg { message: format!("unexpected argument '{}'", arg) });
+            };
+            // Everything up to the next option belongs to this one
+            let mut values = vec![];
+            while let Some(value) = args.next_if(|arg| !arg.starts_with("--")) {
+                values.push(value);
+            }
+            config
+                .set(name, &values)
+                .map_err(|message| ConfigError::Arg { message: format!("--{}: {}", name, message) })?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Apply the directives of a config file on top of the current settings
+    pub fn load(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let file_error = |line, message| ConfigError::File { path: path.to_path_buf(), line, message };
+        let contents = std::fs::read_to_string(path).map_err(|e| file_error(0, e.to_string()))?;
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // Arguments may be quoted the same way as in an inline command
+            let args = frame::split_args(line.as_bytes())
+                .map_err(|_| file_error(i + 1, "unbalanced quotes".to_string()))?
+                .into_iter()
+                .map(|arg| String::from_utf8_lossy(&arg).into_owned())
+                .collect::<Vec<_>>();
+            let Some((name, values)) = args.split_first() else {
+                continue;
+            };
+            self.set(name, values).map_err(|message| file_error(i + 1, format!("'{}': {}", line, message)))?;
+        }
+        Ok(())
+    }
+
+    /// Apply a single directive, e.g. `set("port", &["6380"])`. Directive names are case insensitive.
+    pub fn set(&mut self, name: &str, values: &[String]) -> Result<(), String> {
+        let [value] = values else {
+            return Err(format!("expected one argument, got {}", values.len()));
+        };
+        match &name.to_lowercase()[..] {
+            "bind" => self.bind = value.clone(),
+            "port" => self.port = parse(value)?,
+            "unixsocket" => self.unix_socket = Some(PathBuf::from(value)),
+            "unixsocketperm" => {
+                let perm = u32::from_str_radix(value, 8).map_err(|_| format!("invalid permissions '{}'", value))?;
+                if perm > 0o777 {
+                    return Err(format!("invalid permissions '{}'", value));
+                }
+                self.unix_socket_perm = Some(perm);
+            }
+            "timeout" => self.timeouts.idle = parse_seconds(value)?,
+            "stall-timeout" => self.timeouts.stall = parse_seconds(value)?,
+            "maxclients" => self.max_clients = parse(value)?,
+            "shutdown-timeout" => self.shutdown_timeout = parse_seconds(value)?.unwrap_or(Duration::ZERO),
+            "shards" => self.shards = parse(value)?,
+            "client-query-buffer-limit" => self.limits.max_buffer = parse_memory(value)?,
+            "proto-max-bulk-len" => self.limits.max_bulk_len = parse_memory(value)?,
+            "proto-max-array-len" => self.limits.max_array_len = parse(value)?,
+            "proto-max-depth" => self.limits.max_depth = parse(value)?,
+            "proto-max-inline-len" => self.limits.max_inline_len = parse_memory(value)?,
+            "buffering" => {
+                self.buffering = match &value.to_lowercase()[..] {
+                    "bytes" => Buffering::Bytes,
+                    "vec" => Buffering::Vec,
+                    _ => return Err(format!("invalid buffering '{}', expected 'bytes' or 'vec'", value)),
+                }
+            }
+            "tls-cert-file" => self.tls_cert_file = Some(PathBuf::from(value)),
+            "tls-key-file" => self.tls_key_file = Some(PathBuf::from(value)),
+            "tls-ca-cert-file" => self.tls_ca_cert_file = Some(PathBuf::from(value)),
+            "loglevel" => self.log_level = parse_log_level(value)?,
+            _ => return Err("unknown directive".to_string()),
+        }
+        Ok(())
+    }
+
+    /// Check the settings make sense together
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: &str| Err(ConfigError::Invalid { message: message.to_string() });
+        if self.port == 0 && self.unix_socket.is_none() {
+            return invalid("port is 0 and there is no unixsocket, the server would not listen anywhere");
+        }
+        if self.max_clients == 0 || self.max_clients > Semaphore::MAX_PERMITS {
+            return invalid(&format!("maxclients must be between 1 and {}", Semaphore::MAX_PERMITS));
+        }
+        if self.shards == 0 || self.shards > db::MAX_SHARDS {
+            return invalid(&format!("shards must be between 1 and {}", db::MAX_SHARDS));
+        }
+        if self.limits.max_buffer == 0 || self.limits.max_bulk_len == 0 || self.limits.max_inline_len == 0 {
+            return invalid("client-query-buffer-limit, proto-max-bulk-len and proto-max-inline-len must not be 0");
+        }
+        if self.tls_cert_file.is_some() != self.tls_key_file.is_some() {
+            return invalid("tls-cert-file and tls-key-file must be given together");
+        }
+        if self.tls_ca_cert_file.is_some() && self.tls_cert_file.is_none() {
+            return invalid("tls-ca-cert-file needs tls-cert-file and tls-key-file");
+        }
+        Ok(())
+    }
+
+    /// A `Server` builder with these settings, loading the TLS files if there are any
+    pub fn server_builder(&self) -> crate::Result<Builder> {
+        let mut builder = match self.port {
+            0 => Server::builder().without_tcp(),
+            port => Server::builder().addr(format!("{}:{}", self.bind, port)),
+        };
+        if let Some(path) = &self.unix_socket {
+            builder = builder.unix_socket(path);
+        }
+        if let Some(perm) = self.unix_socket_perm {
+            builder = builder.unix_socket_perm(perm);
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert_file, &self.tls_key_file) {
+            builder = builder.tls(tls::acceptor(cert, key, self.tls_ca_cert_file.as_deref())?);
+        }
+        Ok(builder
+            .timeouts(self.timeouts)
+            .limits(self.limits)
+            .buffering(self.buffering)
+            .max_clients(self.max_clients)
+            .shards(self.shards)
+            .shutdown_timeout(self.shutdown_timeout))
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::File { path, line: 0, message } => write!(fmt, "{}: {}", path.display(), message),
+            ConfigError::File { path, line, message } => write!(fmt, "{}, line {}: {}", path.display(), line, message),
+            ConfigError::Arg { message } | ConfigError::Invalid { message } => message.fmt(fmt),
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid number '{}'", value))
+}
+
+/// Whole seconds, `0` meaning no limit. Like Redis, at most `i32::MAX`, which also keeps deadlines far from overflowing.
+fn parse_seconds(value: &str) -> Result<Option<Duration>, String> {
+    match parse(value)? {
+        0 => Ok(None),
+        secs if secs > i32::MAX as u64 => Err(format!("'{}' is more than {} seconds", value, i32::MAX)),
+        secs => Ok(Some(Duration::from_secs(secs))),
+    }
+}
+
+/// A size in bytes with an optional unit, e.g. `512mb`
+fn parse_memory(value: &str) -> Result<usize, String> {
+    let lower = value.to_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit: usize = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size '{}'", value)),
+    };
+    parse::<usize>(digits)?
+        .checked_mul(unit)
+        .ok_or_else(|| format!("size '{}' is too large", value))
+}
+
+/// Redis' log levels, plus the `tracing` ones
+fn parse_log_level(value: &str) -> Result<Level, String> {
+    match &value.to_lowercase()[..] {
+        "trace" => Ok(Level::TRACE),
+        "debug" => Ok(Level::DEBUG),
+        "verbose" | "notice" | "info" => Ok(Level::INFO),
+        "warning" | "warn" => Ok(Level::WARN),
+        "error" => Ok(Level::ERROR),
+        _ => Err(format!("invalid log level '{}'", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_args(args: &[&str]) -> Result<Config, ConfigError> {
+        Config::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// A config file with `contents`, removed when dropped
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(name: &str, contents: &str) -> ConfigFile {
+            let path = std::env::temp_dir().join(format!("my-redis-config-{}-{}.conf", name, std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            ConfigFile(path)
+        }
+
+        fn load(&self) -> Result<Config, ConfigError> {
+            let mut config = Config::default();
+            config.load(&self.0)?;
+            Ok(config)
+        }
+
+        /// The error for `line` of this file
+        fn error(&self, line: usize, message: &str) -> ConfigError {
+            ConfigError::File { path: self.0.clone(), line, message: message.to_string() }
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn overrides() {
+        let args = ["--port", "6380", "--timeout", "60", "--stall-timeout", "0", "--maxclients", "5"];
+        let config = from_args(&args).unwrap();
+        assert_eq!(config.port, 6380);
+        assert_eq!(config.timeouts, Timeouts { idle: Some(Duration::from_secs(60)), stall: None });
+        assert_eq!(config.max_clients, 5);
+        assert_eq!(config.buffering, Buffering::Bytes);
+
+        let config = from_args(&["--buffering", "VEC"]).unwrap();
+        assert_eq!(config.buffering, Buffering::Vec);
+        assert!(from_args(&["--buffering", "vec_u8"]).is_err());
+    }
+
+    #[test]
+    fn upper_bounds() {
+        let too_many_clients = (Semaphore::MAX_PERMITS + 1).to_string();
+        assert!(from_args(&["--maxclients", &too_many_clients]).is_err());
+        assert!(from_args(&["--maxclients", &Semaphore::MAX_PERMITS.to_string()]).is_ok());
+        assert!(from_args(&["--shards", "1025"]).is_err());
+        assert!(from_args(&["--shards", "1024"]).is_ok());
+        for directive in ["--timeout", "--stall-timeout", "--shutdown-timeout"] {
+            assert!(from_args(&[directive, "18446744073709551615"]).is_err(), "{}", directive);
+            assert!(from_args(&[directive, "2147483648"]).is_err(), "{}", directive);
+            assert!(from_args(&[directive, "2147483647"]).is_ok(), "{}", directive);
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_memory("512mb"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_memory("2K"), Ok(2000));
+        assert!(parse_memory("1xb").is_err());
+        assert!(parse_memory("99999999999999999999gb").is_err());
+    }
+
+    #[test]
+    fn load_file() {
+        let file = ConfigFile::new(
+            "load",
+            "# a comment\n\n   # an indented one\nPORT 6380\n  timeout 60  \nunixsocket \"/tmp/my redis.sock\"\n\
+             unixsocketperm '700'\nbind \"\"\n",
+        );
+        let config = file.load().unwrap();
+        assert_eq!(config.port, 6380);
+        assert_eq!(config.timeouts.idle, Some(Duration::from_secs(60)));
+        assert_eq!(config.unix_socket, Some(PathBuf::from("/tmp/my redis.sock")));
+        assert_eq!(config.unix_socket_perm, Some(0o700));
+        assert_eq!(config.bind, "");
+        // Directives missing from the file keep their defaults
+        assert_eq!(config.max_clients, Config::default().max_clients);
+
+        // The command line overrides the file
+        let config = from_args(&[file.0.to_str().unwrap(), "--port", "7000", "--bind", "0.0.0.0"]).unwrap();
+        assert_eq!((config.port, &config.bind[..]), (7000, "0.0.0.0"));
+        assert_eq!(config.timeouts.idle, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn load_file_errors() {
+        let file = ConfigFile::new("unknown", "port 6380\n# comment\n\nmaxmemory 1gb\nport 6381\n");
+        let err = file.load().unwrap_err();
+        assert_eq!(err, file.error(4, "'maxmemory 1gb': unknown directive"));
+        assert_eq!(err.to_string(), format!("{}, line 4: 'maxmemory 1gb': unknown directive", file.0.display()));
+
+        let file = ConfigFile::new("quotes", "port 6380\nunixsocket \"/tmp/redis.sock\n");
+        assert_eq!(file.load(), Err(file.error(2, "unbalanced quotes")));
+
+        let file = ConfigFile::new("values", "\nport 6380 6381\n");
+        assert_eq!(file.load(), Err(file.error(2, "'port 6380 6381': expected one argument, got 2")));
+        let file = ConfigFile::new("invalid", "# comment\nbind 0.0.0.0\n  port \"x\"\n");
+        assert_eq!(file.load(), Err(file.error(3, "'port \"x\"': invalid number 'x'")));
+
+        // A file that cannot be read has no line to point at
+        let path = std::env::temp_dir().join(format!("my-redis-config-missing-{}.conf", std::process::id()));
+        let err = Config::default().load(&path).unwrap_err();
+        assert!(matches!(err, ConfigError::File { line: 0, .. }));
+        assert!(err.to_string().starts_with(&format!("{}: ", path.display())), "{}", err);
+    }
+}
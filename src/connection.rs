@@ -0,0 +1,272 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::frame::{Frame, Protocol};
+use crate::Result;
+
+/// How long a connection waits on a quiet peer before giving up. `None` waits forever, which is the default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Longest wait for the first byte of the next frame
+    pub idle: Option<Duration>,
+    /// Longest time a partially received frame may take to complete, counted from its first byte. This is what stops
+    /// slowloris-style clients that trickle a frame in one byte at a time.
+    pub stall: Option<Duration>,
+}
+
+impl Timeouts {
+    /// When the current wait for data gives up, and why.
+    ///
+    /// `partial_since` is when the incomplete frame in the read buffer started arriving, `None` if the buffer holds no
+    /// partial frame. A timeout too long to be represented as an `Instant` never fires.
+    pub(crate) fn deadline(&self, partial_since: Option<Instant>) -> Option<(Instant, TimeoutError)> {
+        match partial_since {
+            None => Some((Instant::now().checked_add(self.idle?)?, TimeoutError::Idle)),
+            Some(since) => Some((since.checked_add(self.stall?)?, TimeoutError::Stall)),
+        }
+    }
+}
+
+/// Returned by `read_frame` when one of the connection's `Timeouts` fires.
+///
+/// The connection has already sent the peer an error reply and closed the stream by then.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutError {
+    Idle,
+    Stall,
+}
+
+impl std::error::Error for TimeoutError {}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeoutError::Idle => "idle timeout, closing connection".fmt(fmt),
+            TimeoutError::Stall => "timed out waiting for the rest of the frame, closing connection".fmt(fmt),
+        }
+    }
+}
+
+/// How a connection buffers the bytes it reads and writes, i.e. which `Connection` a server serves its clients with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Buffering {
+    /// `connection_bytes::Connection`: `BytesMut` buffers, large bulk payloads share the read buffer's allocation
+    #[default]
+    Bytes,
+    /// `connection_vec_u8::Connection`: plain `Vec<u8>` buffers, every frame is copied out of the read buffer
+    Vec,
+}
+
+/// A snapshot of a connection's traffic counters, see `stats()` on either `Connection`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Bytes received from the peer, including those of frames not parsed yet
+    pub bytes_read: u64,
+    /// Bytes flushed to the peer
+    pub bytes_written: u64,
+    /// Frames returned by `read_frame`, or by `read_header`
+    pub frames_in: u64,
+    /// Frames queued or written
+    pub frames_out: u64,
+    /// Frames rejected as protocol errors, including ones over the connection's `Limits`
+    pub parse_errors: u64,
+    /// When data last went either way, or when the connection was created if it never did
+    pub last_activity: Instant,
+}
+
+impl ConnectionStats {
+    pub(crate) fn new() -> ConnectionStats {
+        ConnectionStats {
+            bytes_read: 0,
+            bytes_written: 0,
+            frames_in: 0,
+            frames_out: 0,
+            parse_errors: 0,
+            last_activity: Instant::now(),
+        }
+    }
+
+    pub(crate) fn record_read(&mut self, n: usize) {
+        if n > 0 {
+            self.bytes_read += n as u64;
+            self.last_activity = Instant::now();
+        }
+    }
+
+    pub(crate) fn record_write(&mut self, n: usize) {
+        if n > 0 {
+            self.bytes_written += n as u64;
+            self.last_activity = Instant::now();
+        }
+    }
+}
+
+/// Reading frames off a connection.
+///
+/// Implemented by both `connection_bytes::Connection` and `connection_vec_u8::Connection`, so code written against
+/// `FrameRead` and `FrameWrite` works with either buffering strategy.
+pub trait FrameRead {
+    /// Read a single frame, or an inline command as an array of bulk strings.
+    ///
+    /// Frames queued with `FrameWrite::queue_frame` are flushed before it waits for more data, so the replies to a
+    /// pipelined batch go out together. Returns `None` when the peer closed the connection cleanly, between two frames.
+    fn read_frame(&mut self) -> impl Future<Output = Result<Option<Frame>>> + Send;
+
+    /// The connection's traffic counters so far
+    fn stats(&self) -> ConnectionStats;
+
+    /// The timeouts currently applied to the peer
+    fn timeouts(&self) -> Timeouts;
+
+    /// Change the timeouts, e.g. to let a subscriber stay quiet. See `Timeouts`.
+    fn set_timeouts(&mut self, timeouts: Timeouts);
+}
+
+/// Writing frames to a connection. See `FrameRead`.
+pub trait FrameWrite {
+    /// The protocol version currently spoken on this connection
+    fn protocol(&self) -> Protocol;
+
+    fn set_protocol(&mut self, protocol: Protocol);
+
+    /// Encode a frame into the write buffer without touching the socket
+    fn queue_frame(&mut self, frame: &Frame);
+
+    /// Write all queued frames to the socket. Cancel safe, a flush cancelled half way picks up where it stopped.
+    fn flush(&mut self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Write a single frame and flush it, along with anything queued before it
+    fn write_frame(&mut self, frame: &Frame) -> impl Future<Output = Result<()>> + Send;
+
+    /// Handle a `HELLO [protover]` command.
+    ///
+    /// Returns `None` if `frame` is not a `HELLO` command, so the caller can dispatch it as usual. Otherwise the
+    /// protocol version is switched and the reply to send back is returned. The reply is built after switching, so a
+    /// client that asked for RESP3 gets a RESP3 map.
+    fn negotiate(&mut self, frame: &Frame) -> Option<Frame> {
+        let args = match frame {
+            Frame::Array(args) if !args.is_empty() => args,
+            _ => return None,
+        };
+        match &args[0] {
+            Frame::Bulk(name) if name.eq_ignore_ascii_case(b"hello") => {}
+            Frame::Simple(name) if name.eq_ignore_ascii_case("hello") => {}
+            _ => return None,
+        }
+
+        if let Some(option) = args.get(2) {
+            return Some(Frame::Error(format!("ERR Syntax error in HELLO option '{}'", option)));
+        }
+        if let Some(protover) = args.get(1) {
+            match protover.to_string().parse::<i64>() {
+                Ok(2) => self.set_protocol(Protocol::Resp2),
+                Ok(3) => self.set_protocol(Protocol::Resp3),
+                Ok(_) => return Some(Frame::Error("NOPROTO unsupported protocol version".to_string())),
+                Err(_) => return Some(Frame::Error("ERR Protocol version is not an integer or out of range".to_string())),
+            }
+        }
+
+        let proto = match self.protocol() {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        let field = |name: &'static str| Frame::Bulk(name.into());
+        Some(Frame::Map(vec![
+            (field("server"), field("my-redis")),
+            (field("version"), field(env!("CARGO_PKG_VERSION"))),
+            (field("proto"), Frame::Integer(proto)),
+            (field("mode"), field("standalone")),
+            (field("role"), field("master")),
+            (field("modules"), Frame::Array(vec![])),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::task::JoinHandle;
+    use crate::{connection_bytes, connection_vec_u8};
+
+    const BUFFERINGS: [Buffering; 2] = [Buffering::Bytes, Buffering::Vec];
+
+    /// Read frames off the server's end of an in-memory pipe with the `Connection` `buffering` picks, until the client
+    /// hangs up or something fails. The task returns how many frames it read.
+    fn reader(buffering: Buffering, timeouts: Timeouts) -> (DuplexStream, JoinHandle<Result<usize>>) {
+        async fn read_all<C: FrameRead>(mut connection: C, timeouts: Timeouts) -> Result<usize> {
+            connection.set_timeouts(timeouts);
+            let mut frames = 0;
+            while connection.read_frame().await?.is_some() {
+                frames += 1;
+            }
+            Ok(frames)
+        }
+
+        let (client, server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(async move {
+            match buffering {
+                Buffering::Bytes => read_all(connection_bytes::Connection::new(server), timeouts).await,
+                Buffering::Vec => read_all(connection_vec_u8::Connection::new(server), timeouts).await,
+            }
+        });
+        (client, task)
+    }
+
+    /// Check the reader gave up with `expected` after `after`, telling the client why
+    async fn assert_timed_out(
+        mut client: DuplexStream,
+        task: JoinHandle<Result<usize>>,
+        expected: TimeoutError,
+        after: Duration,
+    ) {
+        let start = Instant::now();
+        let err = task.await.unwrap().unwrap_err();
+        assert_eq!(err.downcast_ref::<TimeoutError>(), Some(&expected));
+        assert_eq!(start.elapsed(), after);
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, format!("-ERR {}\r\n", expected));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_client_disconnected() {
+        for buffering in BUFFERINGS {
+            let timeouts = Timeouts { idle: Some(Duration::from_secs(10)), stall: Some(Duration::from_secs(1)) };
+            let (client, task) = reader(buffering, timeouts);
+            assert_timed_out(client, task, TimeoutError::Idle, Duration::from_secs(10)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_frame_disconnected() {
+        for buffering in BUFFERINGS {
+            // The stall timeout applies even without an idle timeout
+            let timeouts = Timeouts { idle: None, stall: Some(Duration::from_secs(5)) };
+            let (mut client, task) = reader(buffering, timeouts);
+            client.write_all(b"*1\r\n$4\r\nPI").await.unwrap();
+            assert_timed_out(client, task, TimeoutError::Stall, Duration::from_secs(5)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_client_kept() {
+        for buffering in BUFFERINGS {
+            let timeouts = Timeouts { idle: Some(Duration::from_secs(10)), stall: Some(Duration::from_secs(5)) };
+            let (mut client, task) = reader(buffering, timeouts);
+            // Every frame trickles in over 4 seconds, with 8 seconds between frames. The client takes longer than
+            // either timeout in all, but never waits longer than one of them.
+            for _ in 0..3 {
+                for chunk in [&b"*1\r\n"[..], b"$4\r\n", b"PI", b"NG\r\n"] {
+                    client.write_all(chunk).await.unwrap();
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                tokio::time::sleep(Duration::from_secs(8)).await;
+            }
+            drop(client);
+            assert_eq!(task.await.unwrap().unwrap(), 3);
+        }
+    }
+}
@@ -1,127 +1,763 @@
-use std::io::Cursor;
-use bytes::{Buf, BytesMut};
-use mini_redis::Frame;
-use mini_redis::frame::Error;
+use std::io::{self, Cursor};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crate::connection::{ConnectionStats, FrameRead, FrameWrite, Timeouts};
+use crate::frame::{format_double, Error, Frame, Limits, Protocol};
 use tokio::net::TcpStream;
-use mini_redis::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Instant;
+use tokio_stream::Stream;
+use tokio_util::io::poll_read_buf;
+use crate::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 
-pub struct Connection {
-    stream: TcpStream,
+/// Frames shorter than this are copied out of the read buffer rather than sharing its allocation, see `decode`
+const MIN_SHARED_FRAME_LEN: usize = 16 * 1024;
+
+/// Reads and writes frames over any byte stream: a `TcpStream`, a `UnixStream`, a TLS stream or an in-memory
+/// `tokio::io::duplex` pipe.
+pub struct Connection<T = TcpStream> {
+    stream: T,
     // Using `BytesMut` instead of `Vec<u8>` allows us to avoid copying data when reading from the socket. `BytesMut` is a buffer type from the `bytes` crate. It is similar to `Vec<u8>`, but it has some additional features that make it more suitable for use as a buffer.
     buffer: BytesMut,
+    // Encoded frames waiting to be written. Frames are encoded here first so that a whole frame, or a whole batch of
+    // pipelined replies, reaches the socket in a single write instead of one write per token.
+    write_buffer: BytesMut,
+    // Protocol version negotiated with `HELLO`. Decides how RESP3-only frames are encoded.
+    protocol: Protocol,
+    // Caps on what the peer may send, so a single client cannot exhaust the server's memory
+    limits: Limits,
+    // How long to wait on a quiet peer
+    timeouts: Timeouts,
+    // When the incomplete frame at the front of the read buffer started arriving, drives the stall timeout
+    partial_since: Option<Instant>,
+    // Traffic counters, see `stats`
+    stats: ConnectionStats,
+    // Bytes of a streamed bulk string, and its trailing `\r\n`, still to be taken off the front of the input
+    discard: usize,
+    // Where the `\r\n` of the streamed bulk string is, counted from the start of the bulk string
+    terminator: usize,
+}
+
+/// The start of the next frame, as returned by `Connection::read_header`
+pub enum Header<'a, T> {
+    /// An array, set or push frame with this many elements. The elements follow and are read with further calls to
+    /// `read_header` or `read_frame`.
+    Array(usize),
+    /// A bulk string, its payload is read through the `BulkReader`
+    Bulk(BulkReader<'a, T>),
+    /// Any other frame, read in full
+    Frame(Frame),
+}
+
+/// The payload of a bulk string returned by `Connection::read_header`, handed out as it arrives.
+///
+/// As an `AsyncRead` the payload is copied into the caller's buffer, e.g. by `tokio::io::copy` into a file. As a
+/// `Stream` it comes out as `Bytes` chunks split off the connection's read buffer without copying. Either way the read
+/// buffer only ever holds one chunk of it. The connection's timeouts do not apply while the payload is read.
+pub struct BulkReader<'a, T> {
+    connection: &'a mut Connection<T>,
+    // Payload bytes not handed out yet
+    remaining: usize,
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Connection {
+impl<T: AsyncRead + AsyncWrite + Unpin> Connection<T> {
+    pub fn new(stream: T) -> Connection<T> {
+        Connection::with_limits(stream, Limits::default())
+    }
+
+    /// Create a connection that rejects frames breaking `limits`
+    pub fn with_limits(stream: T, limits: Limits) -> Connection<T> {
         Connection {
             stream,
             buffer: BytesMut::with_capacity(4096),
+            write_buffer: BytesMut::with_capacity(4096),
+            protocol: Protocol::Resp2,
+            limits,
+            timeouts: Timeouts::default(),
+            partial_since: None,
+            stats: ConnectionStats::new(),
+            discard: 0,
+            terminator: 0,
         }
     }
+
+    /// The timeouts currently applied to the peer
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Give up on a peer that goes quiet for too long, see `Timeouts`
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Returns a reference to the underlying stream
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    /// Consumes the connection, returning the underlying stream. Any buffered data that has not been parsed yet is lost.
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+
+    /// A snapshot of the traffic on this connection so far
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
+    /// The protocol version currently spoken on this connection
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
     pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
         loop {
             // Attempt to parse a frame from the buffered data. If enough data has been buffered, the frame is returned.
-            if let Some(frame) = self.parse_frame()? {
-                return Ok(Some(frame));
-            }
-            // There is not enough buffered data to read a frame. Attempt to read more data from the socket.
-            // On success, the number of bytes is returned. `0` indicates "end of stream".
-            if 0 == self.stream.read_buf(&mut self.buffer).await? {
-                // The remote closed the connection. For this to be a clean shutdown, there should be no data in the read buffer. If there is, this means that the peer closed the socket while sending a frame. This is invalid, so return an error.
-                if self.buffer.is_empty() {
-                    return Ok(None);
-                } else {
-                    return Err("connection reset by peer".into());
+            let parsed = match self.skip_discarded() {
+                Ok(()) => self.parse_frame(),
+                Err(e) => Err(self.protocol_error(e)),
+            };
+            match parsed {
+                Ok(Some(frame)) => {
+                    self.partial_since = None;
+                    self.stats.frames_in += 1;
+                    return Ok(Some(frame));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    // Send the protocol error reply and close the stream. The peer is going away either way, so
+                    // failures here are not interesting.
+                    let _ = self.flush().await;
+                    let _ = self.stream.shutdown().await;
+                    return Err(e);
+                }
+            }
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Read the start of the next frame, leaving the payload of a bulk string to be streamed.
+    ///
+    /// `read_frame` buffers a frame until all of it has arrived. Here a bulk string is returned as soon as its length is
+    /// known, with a `BulkReader` for the payload, and an array only has its length read, its elements are read with
+    /// further calls. A large value can then be written to disk or proxied onward without holding all of it in memory,
+    /// which is also why `Limits::max_bulk_len` does not apply to it. Other frames are read in full.
+    ///
+    /// The `BulkReader` may be dropped before the end of the payload, the rest is skipped by the next read.
+    pub async fn read_header(&mut self) -> Result<Option<Header<'_, T>>> {
+        loop {
+            if let Err(e) = self.skip_discarded() {
+                let e = self.protocol_error(e);
+                let _ = self.flush().await;
+                let _ = self.stream.shutdown().await;
+                return Err(e);
+            }
+            if self.discard == 0 && !self.buffer.is_empty() {
+                let mut cursor = Cursor::new(&self.buffer[..]);
+                let e = match Frame::check_header(&mut cursor, &self.limits) {
+                    Ok(Some((byte, len))) => {
+                        let header_len = cursor.position() as usize;
+                        self.buffer.advance(header_len);
+                        self.partial_since = None;
+                        self.stats.frames_in += 1;
+                        if byte != b'$' {
+                            return Ok(Some(Header::Array(len)));
+                        }
+                        self.discard = len + 2;
+                        self.terminator = header_len + len;
+                        return Ok(Some(Header::Bulk(BulkReader { connection: self, remaining: len })));
+                    }
+                    // A null, or a frame that can only be read whole
+                    Ok(None) => match self.parse_frame() {
+                        Ok(Some(frame)) => {
+                            self.partial_since = None;
+                            self.stats.frames_in += 1;
+                            return Ok(Some(Header::Frame(frame)));
+                        }
+                        Ok(None) => None,
+                        Err(e) => Some(e),
+                    },
+                    Err(Error::Incomplete) => None,
+                    Err(e) => Some(self.protocol_error(e)),
+                };
+                if let Some(e) = e {
+                    let _ = self.flush().await;
+                    let _ = self.stream.shutdown().await;
+                    return Err(e);
                 }
             }
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Wait for more data from the peer. Returns `false` if the peer closed the connection between two frames.
+    async fn fill(&mut self) -> Result<bool> {
+        // There is not enough buffered data to read a frame. Anything queued has to go out now, the peer may be
+        // waiting for those replies before it sends more.
+        if !self.write_buffer.is_empty() {
+            self.flush().await?;
+        }
+        // Attempt to read more data from the socket.
+        // On success, the number of bytes is returned. `0` indicates "end of stream".
+        if 0 == self.read_more().await? {
+            // The remote closed the connection. For this to be a clean shutdown, there should be no data in the read buffer. If there is, this means that the peer closed the socket while sending a frame. This is invalid, so return an error.
+            if self.buffer.is_empty() && self.discard == 0 {
+                return Ok(false);
+            } else {
+                return Err("connection reset by peer".into());
+            }
         }
+        Ok(true)
     }
+
+    /// Read more data from the socket into the read buffer, bounded by the idle or stall timeout
+    async fn read_more(&mut self) -> Result<usize> {
+        if self.buffer.is_empty() && self.discard == 0 {
+            self.partial_since = None;
+        } else if self.partial_since.is_none() {
+            self.partial_since = Some(Instant::now());
+        }
+
+        let Some((deadline, timeout)) = self.timeouts.deadline(self.partial_since) else {
+            let n = self.stream.read_buf(&mut self.buffer).await?;
+            self.stats.record_read(n);
+            return Ok(n);
+        };
+        match tokio::time::timeout_at(deadline, self.stream.read_buf(&mut self.buffer)).await {
+            Ok(n) => {
+                let n = n?;
+                self.stats.record_read(n);
+                Ok(n)
+            }
+            Err(_) => {
+                // Tell the peer why it is being dropped, the same way as for a protocol error
+                self.buffer.clear();
+                self.queue_frame(&Frame::Error(format!("ERR {}", timeout)));
+                let _ = self.flush().await;
+                let _ = self.stream.shutdown().await;
+                Err(timeout.into())
+            }
+        }
+    }
+
     fn parse_frame(&mut self) -> Result<Option<Frame>> {
-        // region 1. Ensure a full frame is buffered and find the end index of the frame
-        // Check the frame using `Frame::check()`
-        // Create the `T: Buf` type
-        let mut buf = Cursor::new(&self.buffer[..]);
-        match Frame::check(&mut buf) {
-            Ok(_) => {
-                // region 2. Parse the frame
-                // Parse the frame using `Frame::parse()`
-                // Get the byte length of the frame
-                let len = buf.position() as usize;
+        decode(&mut self.buffer, &self.limits).map_err(|e| self.protocol_error(e))
+    }
 
-                // Reset the cursor
-                buf.set_position(0);
+    /// The peer sent something we cannot or will not parse, e.g. a frame over the limits. There is no way to find the
+    /// start of the next frame, so tell the peer why and drop the connection, like Redis does. The error reply is
+    /// queued here and flushed by the caller before it closes the stream.
+    fn protocol_error(&mut self, e: Error) -> crate::Error {
+        self.buffer.clear();
+        self.discard = 0;
+        self.stats.parse_errors += 1;
+        self.queue_frame(&Frame::Error(format!("ERR {}", e)));
+        e.into()
+    }
+    /// Write a single frame and flush it to the socket.
+    ///
+    /// Any frames queued with `queue_frame` are flushed along with it.
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.queue_frame(frame);
+        self.flush().await
+    }
 
-                // Parse the frame
-                let frame = Frame::parse(&mut buf)?;
+    /// Encode a frame into the write buffer without touching the socket.
+    ///
+    /// Queued frames go out on the next `flush` or `write_frame`, or before `read_frame` has to wait for more data.
+    /// Replies to a pipelined batch of commands therefore leave in one write.
+    pub fn queue_frame(&mut self, frame: &Frame) {
+        self.stats.frames_out += 1;
+        encode(frame, self.protocol, &mut self.write_buffer);
+    }
 
-                // Discard the frame from the buffer
-                self.buffer.advance(len);
+    /// Write all queued frames to the socket.
+    ///
+    /// Cancel safe: bytes leave the write buffer as they are written, so a flush cancelled half way, e.g. by a
+    /// `select!`, picks up where it stopped on the next call.
+    pub async fn flush(&mut self) -> Result<()> {
+        while !self.write_buffer.is_empty() {
+            // `write_buf` advances the buffer past the bytes written
+            let n = self.stream.write_buf(&mut self.write_buffer).await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            self.stats.record_write(n);
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
 
-                // Return the frame to the caller
-                Ok(Some(frame))
+impl<T> Connection<T> {
+    /// Drop what is left of a streamed bulk string that was not read to the end, and the `\r\n` after it. The
+    /// terminator is checked the same way `Frame::check` checks it.
+    fn skip_discarded(&mut self) -> std::result::Result<(), Error> {
+        let payload = self.discard.saturating_sub(2).min(self.buffer.len());
+        self.buffer.advance(payload);
+        self.discard -= payload;
+        while self.discard > 0 && !self.buffer.is_empty() {
+            let (expected, offset) = match self.discard {
+                2 => (b'\r', self.terminator),
+                _ => (b'\n', self.terminator + 1),
+            };
+            if self.buffer[0] != expected {
+                return Err(Error::UnexpectedByte { byte: self.buffer[0], offset });
+            }
+            self.buffer.advance(1);
+            self.discard -= 1;
+        }
+        Ok(())
+    }
+}
 
-                // endregion
+impl<T: AsyncRead + Unpin> BulkReader<'_, T> {
+    /// Number of payload bytes not read yet
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Make sure some of the payload is buffered, returning how much of it. `0` means the payload has been read, and
+    /// was followed by `\r\n`.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        if self.remaining == 0 {
+            while self.connection.discard > 0 {
+                if self.connection.buffer.is_empty() {
+                    ready!(self.poll_read_more(cx, 2))?;
+                }
+                self.connection
+                    .skip_discarded()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
             }
-            // Not enough data has been buffered
-            Err(Error::Incomplete) => Ok(None),
-            Err(e) => Err(e.into()),
+            return Poll::Ready(Ok(0));
+        }
+        if self.connection.buffer.is_empty() {
+            // Whatever arrives past the end of the payload stays buffered for the next frame
+            ready!(self.poll_read_more(cx, self.remaining.min(64 * 1024)))?;
+        }
+        Poll::Ready(Ok(self.remaining.min(self.connection.buffer.len())))
+    }
+
+    /// Read more of the input into the connection's read buffer, which has run empty
+    fn poll_read_more(&mut self, cx: &mut Context<'_>, reserve: usize) -> Poll<io::Result<()>> {
+        let connection = &mut *self.connection;
+        connection.buffer.reserve(reserve);
+        let n = ready!(poll_read_buf(Pin::new(&mut connection.stream), cx, &mut connection.buffer))?;
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
         }
-        // endregion
+        connection.stats.record_read(n);
+        Poll::Ready(Ok(()))
+    }
+
+    /// Take `n` bytes of the payload off the front of the read buffer
+    fn take(&mut self, n: usize) -> BytesMut {
+        self.remaining -= n;
+        self.connection.discard -= n;
+        self.connection.buffer.split_to(n)
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for BulkReader<'_, T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let n = ready!(this.poll_fill(cx))?.min(buf.remaining());
+        buf.put_slice(&this.take(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> Stream for BulkReader<'_, T> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Bytes>>> {
+        let this = self.get_mut();
+        match ready!(this.poll_fill(cx)) {
+            Ok(0) => Poll::Ready(None),
+            Ok(n) => Poll::Ready(Some(Ok(this.take(n).freeze()))),
+            Err(e) => Poll::Ready(Some(Err(e))),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> FrameRead for Connection<T> {
+    async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        Connection::read_frame(self).await
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        Connection::stats(self)
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        Connection::timeouts(self)
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) {
+        Connection::set_timeouts(self, timeouts)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> FrameWrite for Connection<T> {
+    fn protocol(&self) -> Protocol {
+        Connection::protocol(self)
     }
+
+    fn set_protocol(&mut self, protocol: Protocol) {
+        Connection::set_protocol(self, protocol)
+    }
+
+    fn queue_frame(&mut self, frame: &Frame) {
+        Connection::queue_frame(self, frame)
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        Connection::flush(self).await
+    }
+
     async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
-        // https://redis.io/docs/reference/protocol-spec/
-        match frame {
-            // Simple Strings
-            Frame::Simple(val) => {
-                self.stream.write_u8(b'+').await?;
-                self.stream.write_all(val.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            // Simple Errors
-            Frame::Error(val) => {
-                self.stream.write_u8(b'-').await?;
-                self.stream.write_all(val.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            // Integers
-            Frame::Integer(val) => {
-                self.stream.write_u8(b':').await?;
-                self.write_decimal(*val).await?;
-            }
-            // Bulk Strings
-            Frame::Bulk(val) => {
-                let len = val.len();
-                self.stream.write_u8(b'$').await?;
-                self.write_decimal(len as u64).await?;
-                self.stream.write_all(val).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            // Null Bulk Strings
-            Frame::Null => {
-                self.stream.write_all(b"$-1\r\n").await?;
-            }
-            Frame::Array(_val) => unimplemented!("Array"),
+        Connection::write_frame(self, frame).await
+    }
+}
+
+/// Parse a frame or an inline command from the front of `buffer`, discarding its bytes once it has been parsed.
+///
+/// Returns `None` if `buffer` does not hold a complete frame yet. Shared by `Connection` and `RespCodec`.
+pub(crate) fn decode(buffer: &mut BytesMut, limits: &Limits) -> std::result::Result<Option<Frame>, Error> {
+    // Blank inline lines do not produce a frame, so keep going until one does or the buffer runs dry
+    while let Some(len) = frame_len(&buffer[..], limits)? {
+        // Split the frame off the front of the buffer. `Frame::parse()` hands out bulk payloads as slices of it
+        // rather than copies, and a value stored that way keeps all of the allocation it is a slice of alive. Freezing
+        // the frame shares the read buffer's allocation, so that is only done for a large frame that fills most of
+        // it. Anything else is copied out once, into an allocation of its own that its payloads then share.
+        let capacity = buffer.capacity();
+        let frame = buffer.split_to(len);
+        let src = if len >= MIN_SHARED_FRAME_LEN && len >= capacity / 2 {
+            frame.freeze()
+        } else {
+            Bytes::copy_from_slice(&frame)
+        };
+        if let Some(frame) = parse_complete(src)? {
+            return Ok(Some(frame));
         }
-        self.stream.flush().await?;
-        Ok(())
+    }
+    Ok(None)
+}
+
+/// Find the length of the frame or inline command at the front of `buf`.
+///
+/// Returns `None` if `buf` does not hold a complete frame yet.
+pub(crate) fn frame_len(buf: &[u8], limits: &Limits) -> std::result::Result<Option<usize>, Error> {
+    // Frames that do not start with a RESP type byte are inline commands, which are a single line each
+    if buf.first().is_some_and(|byte| !Frame::is_type_byte(*byte)) {
+        return match buf.iter().position(|byte| *byte == b'\n') {
+            Some(end) if end <= limits.max_inline_len => Ok(Some(end + 1)),
+            Some(_) => Err(Error::InlineTooLong { offset: limits.max_inline_len }),
+            None if buf.len() > limits.max_inline_len => Err(Error::InlineTooLong { offset: limits.max_inline_len }),
+            // The rest of the line has not arrived yet
+            None => Ok(None),
+        };
     }
 
-    /// Write a decimal frame to the stream
-    async fn write_decimal(&mut self, val: u64) -> Result<()> {
-        use std::io::Write;
+    // region 1. Ensure a full frame is buffered and find the end index of the frame
+    // Check the frame using `Frame::check()`
+    // Create the `T: Buf` type
+    let mut cursor = Cursor::new(buf);
+    match Frame::check(&mut cursor, limits) {
+        // Get the byte length of the frame
+        Ok(_) => Ok(Some(cursor.position() as usize)),
+        // Not enough data has been buffered. A frame that keeps growing without completing is cut off at the limit.
+        Err(Error::Incomplete) if buf.len() > limits.max_buffer => Err(Error::BufferFull { offset: limits.max_buffer }),
+        Err(Error::Incomplete) => Ok(None),
+        Err(e) => Err(e),
+    }
+    // endregion
+}
 
-        // Convert the value to a string
-        let mut buf = [0u8; 12];
-        let mut buf = Cursor::new(&mut buf[..]);
-        // Write the string to the buffer
-        write!(&mut buf, "{}", val)?;
-        // Write the string to the stream
-        let pos = buf.position() as usize;
-        self.stream.write_all(&buf.get_ref()[..pos]).await?;
-        self.stream.write_all(b"\r\n").await?;
+/// Parse a complete frame or inline command, as measured by `frame_len`.
+///
+/// Returns `None` for a blank inline line, which Redis skips.
+pub(crate) fn parse_complete(src: Bytes) -> std::result::Result<Option<Frame>, Error> {
+    // region 2. Parse the frame
+    if src.first().is_some_and(|byte| !Frame::is_type_byte(*byte)) {
+        // Strip the `\n`, `parse_inline` takes care of a `\r` before it
+        return Frame::parse_inline(&src[..src.len() - 1]);
+    }
+    // Parse the frame using `Frame::parse()`
+    Frame::parse(&mut Cursor::new(src)).map(Some)
+    // endregion
+}
 
-        Ok(())
+/// Encode `frame` into `dst` as the given protocol version. Shared by both `Connection`s and `RespCodec`.
+///
+/// Encoding is synchronous, so nested frames are handled with plain recursion.
+pub(crate) fn encode<B: BufMut>(frame: &Frame, protocol: Protocol, dst: &mut B) {
+    // https://redis.io/docs/reference/protocol-spec/
+    match frame {
+        // Simple Strings
+        Frame::Simple(val) => encode_line(b'+', val, dst),
+        // Simple Errors
+        Frame::Error(val) => encode_line(b'-', val, dst),
+        // Integers
+        Frame::Integer(val) => encode_decimal(b':', *val, dst),
+        // Bulk Strings
+        Frame::Bulk(val) => encode_blob(b'$', val, dst),
+        // Null, `$-1` (the null bulk string) is the closest RESP2 has
+        Frame::Null => match protocol {
+            Protocol::Resp2 => dst.put_slice(b"$-1\r\n"),
+            Protocol::Resp3 => dst.put_slice(b"_\r\n"),
+        },
+        // Arrays
+        Frame::Array(val) => encode_aggregate(b'*', val, protocol, dst),
+        // Maps are flattened into an array of alternating keys and values on RESP2
+        Frame::Map(pairs) => {
+            match protocol {
+                Protocol::Resp2 => encode_decimal(b'*', 2 * pairs.len() as i64, dst),
+                Protocol::Resp3 => encode_decimal(b'%', pairs.len() as i64, dst),
+            }
+            encode_pairs(pairs, protocol, dst);
+        }
+        // Sets
+        Frame::Set(val) => match protocol {
+            Protocol::Resp2 => encode_aggregate(b'*', val, protocol, dst),
+            Protocol::Resp3 => encode_aggregate(b'~', val, protocol, dst),
+        },
+        // Doubles are sent as bulk strings on RESP2
+        Frame::Double(val) => {
+            let val = format_double(*val);
+            match protocol {
+                Protocol::Resp2 => encode_blob(b'$', val.as_bytes(), dst),
+                Protocol::Resp3 => encode_line(b',', &val, dst),
+            }
+        }
+        // Booleans are sent as the integers `1` and `0` on RESP2
+        Frame::Boolean(val) => match protocol {
+            Protocol::Resp2 => encode_decimal(b':', *val as i64, dst),
+            Protocol::Resp3 => encode_line(b'#', if *val { "t" } else { "f" }, dst),
+        },
+        // Big numbers are sent as bulk strings on RESP2
+        Frame::BigNumber(val) => match protocol {
+            Protocol::Resp2 => encode_blob(b'$', val.as_bytes(), dst),
+            Protocol::Resp3 => encode_line(b'(', val, dst),
+        },
+        // Verbatim strings lose their format on RESP2
+        Frame::Verbatim { format, data } => match protocol {
+            Protocol::Resp2 => encode_blob(b'$', data, dst),
+            Protocol::Resp3 => {
+                encode_decimal(b'=', (format.len() + 1 + data.len()) as i64, dst);
+                dst.put_slice(format.as_bytes());
+                dst.put_u8(b':');
+                dst.put_slice(data);
+                dst.put_slice(b"\r\n");
+            }
+        },
+        // RESP2 has no way to carry attributes, so only the frame they describe is written
+        Frame::Attribute { attributes, data } => {
+            if protocol == Protocol::Resp3 {
+                encode_decimal(b'|', attributes.len() as i64, dst);
+                encode_pairs(attributes, protocol, dst);
+            }
+            encode(data, protocol, dst);
+        }
+        // Push frames are sent as arrays on RESP2, which is what pub/sub messages look like there
+        Frame::Push(val) => match protocol {
+            Protocol::Resp2 => encode_aggregate(b'*', val, protocol, dst),
+            Protocol::Resp3 => encode_aggregate(b'>', val, protocol, dst),
+        },
     }
 }
 
+/// Encode a single line frame, e.g. a simple string
+fn encode_line<B: BufMut>(prefix: u8, val: &str, dst: &mut B) {
+    dst.put_u8(prefix);
+    dst.put_slice(val.as_bytes());
+    dst.put_slice(b"\r\n");
+}
+
+/// Encode a length prefixed blob, e.g. a bulk string
+fn encode_blob<B: BufMut>(prefix: u8, val: &[u8], dst: &mut B) {
+    encode_decimal(prefix, val.len() as i64, dst);
+    dst.put_slice(val);
+    dst.put_slice(b"\r\n");
+}
+
+/// Encode an aggregate frame: the `prefix` and element count followed by each element
+fn encode_aggregate<B: BufMut>(prefix: u8, val: &[Frame], protocol: Protocol, dst: &mut B) {
+    encode_decimal(prefix, val.len() as i64, dst);
+    for entry in val {
+        encode(entry, protocol, dst);
+    }
+}
+
+/// Encode the key/value pairs of a map or attribute frame
+fn encode_pairs<B: BufMut>(pairs: &[(Frame, Frame)], protocol: Protocol, dst: &mut B) {
+    for (key, value) in pairs {
+        encode(key, protocol, dst);
+        encode(value, protocol, dst);
+    }
+}
+
+/// Encode `prefix` followed by a decimal and `\r\n`, e.g. an integer frame or a length header
+fn encode_decimal<B: BufMut>(prefix: u8, val: i64, dst: &mut B) {
+    use std::io::Write;
+
+    // Convert the value to a string. `i64::MIN` is 20 characters long.
+    let mut buf = [0u8; 20];
+    let mut buf = Cursor::new(&mut buf[..]);
+    write!(&mut buf, "{}", val).expect("an i64 fits in 20 bytes");
+    let pos = buf.position() as usize;
+
+    dst.put_u8(prefix);
+    dst.put_slice(&buf.get_ref()[..pos]);
+    dst.put_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::io::StreamReader;
+
+    /// A connection whose reads hand out `chunks` one at a time, as if each arrived in its own TCP segment
+    fn chunked(chunks: Vec<&[u8]>) -> Connection<impl AsyncRead + AsyncWrite + Unpin> {
+        let chunks: Vec<_> = chunks.into_iter().map(|chunk| io::Result::Ok(Bytes::copy_from_slice(chunk))).collect();
+        let reader = StreamReader::new(futures::stream::iter(chunks));
+        Connection::new(tokio::io::join(reader, tokio::io::sink()))
+    }
+
+    fn command(args: &[&str]) -> Frame {
+        Frame::Array(args.iter().map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes()))).collect())
+    }
+
+    #[tokio::test]
+    async fn frame_split_at_every_offset() {
+        let inputs: [&[u8]; 2] = [b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$7\r\nbar baz\r\n", b"SET foo \"bar baz\"\r\n"];
+        for input in inputs {
+            for split in 1..input.len() {
+                let mut connection = chunked(vec![&input[..split], &input[split..]]);
+                let frame = connection.read_frame().await.unwrap();
+                assert_eq!(frame, Some(command(&["SET", "foo", "bar baz"])), "split at {}", split);
+                assert_eq!(connection.read_frame().await.unwrap(), None, "split at {}", split);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn inline_commands_between_frames() {
+        let mut connection = chunked(vec![b"PING\r\n\r\n*1\r\n$4\r\nPING\r\nECHO 'a b'\n"]);
+        assert_eq!(connection.read_frame().await.unwrap(), Some(command(&["PING"])));
+        assert_eq!(connection.read_frame().await.unwrap(), Some(command(&["PING"])));
+        assert_eq!(connection.read_frame().await.unwrap(), Some(command(&["ECHO", "a b"])));
+        assert_eq!(connection.read_frame().await.unwrap(), None);
+    }
+
+    /// Read the next header, expecting a bulk string, and read its payload to the end
+    async fn read_bulk<T: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<T>) -> io::Result<Vec<u8>> {
+        let Some(Header::Bulk(mut reader)) = connection.read_header().await.unwrap() else {
+            panic!("expected a bulk string");
+        };
+        let mut payload = vec![];
+        reader.read_to_end(&mut payload).await?;
+        Ok(payload)
+    }
+
+    #[tokio::test]
+    async fn streamed_bulk_strings() {
+        let mut connection = chunked(vec![b"$5\r\nhel", b"lo\r", b"\n$0\r\n\r\n", b"$3\r\nabc\r\n+OK\r\n"]);
+        assert_eq!(read_bulk(&mut connection).await.unwrap(), b"hello");
+        assert_eq!(read_bulk(&mut connection).await.unwrap(), b"");
+        // The rest of a payload is skipped when its reader goes out of scope early
+        {
+            let Some(Header::Bulk(mut reader)) = connection.read_header().await.unwrap() else {
+                panic!("expected a bulk string");
+            };
+            assert_eq!(reader.read_u8().await.unwrap(), b'a');
+        }
+        assert_eq!(connection.read_frame().await.unwrap(), Some(Frame::Simple("OK".to_string())));
+    }
+
+    #[tokio::test]
+    async fn streamed_bulk_string_terminator() {
+        let mut connection = chunked(vec![b"$3\r\nabc", b"XY"]);
+        let err = read_bulk(&mut connection).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Protocol error: unexpected byte 'X' at offset 7");
+        // The connection gives up on the peer, the same as if `read_frame` had found the bad terminator
+        assert!(connection.read_header().await.is_err());
+
+        // A payload that was not read to the end is checked when it is skipped
+        let mut connection = chunked(vec![b"$3\r\nabc\rY+OK\r\n"]);
+        assert!(matches!(connection.read_header().await.unwrap(), Some(Header::Bulk(_))));
+        let err = connection.read_frame().await.unwrap_err();
+        assert_eq!(err.to_string(), "Protocol error: unexpected byte 'Y' at offset 8");
+        assert_eq!(connection.stats().parse_errors, 1);
+    }
+
+    #[test]
+    fn small_frames_are_copied_once() {
+        /// Decode the bulk string in `buffer`, returning whether its payload is a slice of `buffer`
+        fn shares_buffer(mut buffer: BytesMut) -> bool {
+            let range = buffer.as_ptr_range();
+            let Some(Frame::Bulk(payload)) = decode(&mut buffer, &Limits::default()).unwrap() else {
+                panic!("expected a bulk string");
+            };
+            range.contains(&payload.as_ptr())
+        }
+        let bulk = |len: usize, capacity: usize| {
+            let mut buffer = BytesMut::with_capacity(capacity);
+            buffer.extend_from_slice(format!("${}\r\n", len).as_bytes());
+            buffer.extend_from_slice(&vec![b'x'; len]);
+            buffer.extend_from_slice(b"\r\n");
+            buffer
+        };
+
+        // The payload of a large frame is a slice of the read buffer, unless the buffer is much larger than the frame
+        assert!(shares_buffer(bulk(MIN_SHARED_FRAME_LEN, 0)));
+        assert!(!shares_buffer(bulk(MIN_SHARED_FRAME_LEN, 64 * MIN_SHARED_FRAME_LEN)));
+        assert!(!shares_buffer(bulk(MIN_SHARED_FRAME_LEN - 20, 0)));
+
+        // The payloads of a small frame are slices of a single copy of it
+        let mut buffer = BytesMut::from(&b"*3\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n"[..]);
+        let range = buffer.as_ptr_range();
+        let Some(Frame::Array(items)) = decode(&mut buffer, &Limits::default()).unwrap() else {
+            panic!("expected an array");
+        };
+        let pointers: Vec<_> = items
+            .iter()
+            .map(|item| match item {
+                Frame::Bulk(payload) => payload.as_ptr(),
+                item => panic!("unexpected frame {:?}", item),
+            })
+            .collect();
+        assert!(pointers.iter().all(|pointer| !range.contains(pointer)));
+        // Laid out the same as in the frame
+        assert_eq!(pointers[1] as usize - pointers[0] as usize, 10);
+        assert_eq!(pointers[2] as usize - pointers[1] as usize, 7);
+    }
+
+    #[test]
+    fn inline_length_limit() {
+        let limits = Limits { max_inline_len: 8, ..Limits::default() };
+        assert_eq!(frame_len(b"GET foo\r\n", &limits), Ok(Some(9)));
+        assert_eq!(frame_len(b"GET foo", &limits), Ok(None));
+        assert_eq!(frame_len(b"GET foobar\r\n", &limits), Err(Error::InlineTooLong { offset: 8 }));
+        // An overlong line is turned away before its end arrives
+        assert_eq!(frame_len(b"GET foobar", &limits), Err(Error::InlineTooLong { offset: 8 }));
+    }
+
+    #[test]
+    fn buffer_limit() {
+        let limits = Limits { max_buffer: 8, ..Limits::default() };
+        assert_eq!(frame_len(b"$4\r\nab", &limits), Ok(None));
+        assert_eq!(frame_len(b"$20\r\nabcdef", &limits), Err(Error::BufferFull { offset: 8 }));
+    }
+}
@@ -1,38 +1,136 @@
-use std::io::Cursor;
-use bytes::{BufMut, BytesMut};
+use std::io;
+use bytes::Bytes;
 use tokio::net::TcpStream;
-use tokio::io::AsyncReadExt;
-use mini_redis::{Frame, Result};
-use mini_redis::frame::Error;
+use tokio::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::connection::{ConnectionStats, FrameRead, FrameWrite, Timeouts};
+use crate::connection_bytes::{encode, frame_len, parse_complete};
+use crate::Result;
+use crate::frame::{Error, Frame, Limits, Protocol};
 
-pub struct Connection {
-    stream: TcpStream,
+/// The same framing as `connection_bytes::Connection`, buffered in plain `Vec<u8>`s.
+///
+/// Bytes are read into a zero-filled `Vec<u8>`. Parsed frames are skipped over, and what is left of the buffer is moved
+/// to the front once before each read, so a pipelined batch costs one move rather than one per frame. Every frame is
+/// copied out of the buffer, so bulk payloads do not share the read buffer's allocation the way they do with `BytesMut`.
+pub struct Connection<T = TcpStream> {
+    stream: T,
     buffer: Vec<u8>,
+    // Start of the data not parsed yet. Everything before it belongs to frames already returned.
+    start: usize,
+    // Number of bytes in `buffer` that hold data read from the socket. Everything after it is free space.
     cursor: usize,
+    // Encoded frames waiting to be written
+    write_buffer: Vec<u8>,
+    // Protocol version negotiated with `HELLO`. Decides how RESP3-only frames are encoded.
+    protocol: Protocol,
+    // Caps on what the peer may send, so a single client cannot exhaust the server's memory
+    limits: Limits,
+    // How long to wait on a quiet peer
+    timeouts: Timeouts,
+    // When the incomplete frame at the front of the read buffer started arriving, drives the stall timeout
+    partial_since: Option<Instant>,
+    // Traffic counters, see `stats`
+    stats: ConnectionStats,
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Connection {
+impl<T: AsyncRead + AsyncWrite + Unpin> Connection<T> {
+    pub fn new(stream: T) -> Connection<T> {
+        Connection::with_limits(stream, Limits::default())
+    }
+
+    /// Create a connection that rejects frames breaking `limits`
+    pub fn with_limits(stream: T, limits: Limits) -> Connection<T> {
         Connection {
             stream,
             // buffer with 4kb capacity
             buffer: vec![0; 4096],
+            start: 0,
             cursor: 0,
+            write_buffer: Vec::with_capacity(4096),
+            protocol: Protocol::Resp2,
+            limits,
+            timeouts: Timeouts::default(),
+            partial_since: None,
+            stats: ConnectionStats::new(),
         }
     }
+
+    /// The timeouts currently applied to the peer
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Give up on a peer that goes quiet for too long, see `Timeouts`
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Returns a reference to the underlying stream
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    /// Consumes the connection, returning the underlying stream. Any buffered data that has not been parsed yet is lost.
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+
+    /// A snapshot of the traffic on this connection so far
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
+    /// The protocol version currently spoken on this connection
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
     pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
         loop {
             // Attempt to parse a frame from the buffered data. If enough data has been buffered, the frame is returned.
-            if let Some(frame) = self.parse_frame()? {
-                return Ok(Some(frame));
+            match self.parse_frame() {
+                Ok(Some(frame)) => {
+                    self.partial_since = None;
+                    self.stats.frames_in += 1;
+                    return Ok(Some(frame));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    // Send the protocol error reply and close the stream. The peer is going away either way, so
+                    // failures here are not interesting.
+                    let _ = self.flush().await;
+                    let _ = self.stream.shutdown().await;
+                    return Err(e);
+                }
+            }
+            // There is not enough buffered data to read a frame. Anything queued has to go out now, the peer may be
+            // waiting for those replies before it sends more.
+            if !self.write_buffer.is_empty() {
+                self.flush().await?;
             }
+            // Make room for the read by dropping the frames parsed so far
+            self.compact();
             // Ensure the buffer has capacity
             if self.cursor == self.buffer.len() {
-                // Grow the buffer if needed
-                self.buffer.resize(self.cursor * 2, 0);
+                // A frame that keeps growing without completing is cut off at the limit
+                if self.cursor >= self.limits.max_buffer {
+                    let e = Error::BufferFull { offset: self.cursor };
+                    self.stats.parse_errors += 1;
+                    self.queue_frame(&Frame::Error(format!("ERR {}", e)));
+                    let _ = self.flush().await;
+                    let _ = self.stream.shutdown().await;
+                    return Err(e.into());
+                }
+                // Grow the buffer if needed, but never past the limit
+                self.buffer.resize((self.cursor * 2).min(self.limits.max_buffer), 0);
             }
             // Read into the buffer, tracking the number of bytes read
-            let n = self.stream.read(&mut self.buffer[self.cursor..]).await?;
+            let n = self.read_more().await?;
 
             // If `n` is 0, the remote closed the connection.
             if n == 0 {
@@ -50,37 +148,160 @@ impl Connection {
         }
     }
 
+    /// Move the data not parsed yet to the front of the buffer
+    fn compact(&mut self) {
+        if self.start > 0 {
+            self.buffer.copy_within(self.start..self.cursor, 0);
+            self.cursor -= self.start;
+            self.start = 0;
+        }
+    }
+
+    /// Read more data from the socket into the free space of the buffer, bounded by the idle or stall timeout. The
+    /// buffer has been compacted.
+    async fn read_more(&mut self) -> Result<usize> {
+        if self.cursor == 0 {
+            self.partial_since = None;
+        } else if self.partial_since.is_none() {
+            self.partial_since = Some(Instant::now());
+        }
+
+        let Some((deadline, timeout)) = self.timeouts.deadline(self.partial_since) else {
+            let n = self.stream.read(&mut self.buffer[self.cursor..]).await?;
+            self.stats.record_read(n);
+            return Ok(n);
+        };
+        match tokio::time::timeout_at(deadline, self.stream.read(&mut self.buffer[self.cursor..])).await {
+            Ok(n) => {
+                let n = n?;
+                self.stats.record_read(n);
+                Ok(n)
+            }
+            Err(_) => {
+                // Tell the peer why it is being dropped, the same way as for a protocol error
+                self.start = 0;
+                self.cursor = 0;
+                self.queue_frame(&Frame::Error(format!("ERR {}", timeout)));
+                let _ = self.flush().await;
+                let _ = self.stream.shutdown().await;
+                Err(timeout.into())
+            }
+        }
+    }
+
     fn parse_frame(&mut self) -> Result<Option<Frame>> {
-        // region 1. Ensure a full frame is buffered and find the end index of the frame
-        // Check the frame using `Frame::check()`
-        // Create the `T: Buf` type
-        let mut buf = Cursor::new(&self.buffer[..]);
-        match Frame::check(&mut buf) {
-            Ok(_) => {
-                // region 2. Parse the frame
-                // Parse the frame using `Frame::parse()`
-                // Get the byte length of the frame
-                let len = buf.position() as usize;
-
-                // Reset the cursor
-                buf.set_position(0);
-
-                // Parse the frame
-                let frame = Frame::parse(&mut buf)?;
-
-                // Discard the frame from the buffer
-                unsafe { self.buffer.advance_mut(len); }
-
-                // Return the frame to the caller
-                Ok(Some(frame))
-
-                // endregion
+        // Blank inline lines do not produce a frame, so keep going until one does or the buffer runs dry
+        loop {
+            // region 1. Ensure a full frame is buffered and find the end index of the frame
+            // Only `buffer[start..cursor]` holds data not parsed yet, the rest is parsed frames and free space
+            let len = match frame_len(&self.buffer[self.start..self.cursor], &self.limits) {
+                Ok(Some(len)) => len,
+                // Not enough data has been buffered
+                Ok(None) => return Ok(None),
+                Err(e) => return Err(self.protocol_error(e)),
+            };
+            // endregion
+
+            // region 2. Parse the frame
+            // Copy the frame out of the buffer. Unlike `BytesMut`, a `Vec<u8>` cannot hand out slices that outlive it.
+            let frame = parse_complete(Bytes::copy_from_slice(&self.buffer[self.start..self.start + len]));
+
+            // Skip over the frame. Once everything has been parsed the buffer is empty again and nothing needs moving.
+            self.start += len;
+            if self.start == self.cursor {
+                self.start = 0;
+                self.cursor = 0;
             }
-            // Not enough data has been buffered
-            Err(Error::Incomplete) => Ok(None),
-            Err(e) => Err(e.into()),
+
+            match frame {
+                Ok(Some(frame)) => return Ok(Some(frame)),
+                Ok(None) => continue,
+                Err(e) => return Err(self.protocol_error(e)),
+            }
+            // endregion
         }
-        // endregion
+    }
+
+    /// The peer sent something we cannot or will not parse. There is no way to find the start of the next frame, so
+    /// queue an error reply for `read_frame` to flush before it closes the stream, like Redis does.
+    fn protocol_error(&mut self, e: Error) -> crate::Error {
+        self.start = 0;
+        self.cursor = 0;
+        self.stats.parse_errors += 1;
+        self.queue_frame(&Frame::Error(format!("ERR {}", e)));
+        e.into()
+    }
+
+    /// Write a single frame and flush it to the socket.
+    ///
+    /// Any frames queued with `queue_frame` are flushed along with it.
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        self.queue_frame(frame);
+        self.flush().await
+    }
+
+    /// Encode a frame into the write buffer without touching the socket.
+    ///
+    /// Queued frames go out on the next `flush` or `write_frame`, or before `read_frame` has to wait for more data.
+    pub fn queue_frame(&mut self, frame: &Frame) {
+        self.stats.frames_out += 1;
+        encode(frame, self.protocol, &mut self.write_buffer);
+    }
+
+    /// Write all queued frames to the socket.
+    ///
+    /// Cancel safe: bytes leave the write buffer as they are written, so a flush cancelled half way, e.g. by a
+    /// `select!`, picks up where it stopped on the next call.
+    pub async fn flush(&mut self) -> Result<()> {
+        while !self.write_buffer.is_empty() {
+            let n = self.stream.write(&self.write_buffer).await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
+            self.write_buffer.drain(..n);
+            self.stats.record_write(n);
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> FrameRead for Connection<T> {
+    async fn read_frame(&mut self) -> Result<Option<Frame>> {
+        Connection::read_frame(self).await
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        Connection::stats(self)
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        Connection::timeouts(self)
+    }
+
+    fn set_timeouts(&mut self, timeouts: Timeouts) {
+        Connection::set_timeouts(self, timeouts)
     }
 }
 
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> FrameWrite for Connection<T> {
+    fn protocol(&self) -> Protocol {
+        Connection::protocol(self)
+    }
+
+    fn set_protocol(&mut self, protocol: Protocol) {
+        Connection::set_protocol(self, protocol)
+    }
+
+    fn queue_frame(&mut self, frame: &Frame) {
+        Connection::queue_frame(self, frame)
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        Connection::flush(self).await
+    }
+
+    async fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        Connection::write_frame(self, frame).await
+    }
+}
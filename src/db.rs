@@ -0,0 +1,726 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use bytes::Bytes;
+use tokio::sync::{broadcast, Notify};
+use tokio::time::{self, Instant};
+use crate::glob;
+
+/// The key/value store shared by every connection of a `Server`.
+///
+/// Cloning a `Db` is cheap, all clones share the same data. The keys are split over a number of shards, each behind its
+/// own lock, so connections working on different keys rarely wait on each other. Keys may be given a deadline, after
+/// which they are gone.
+/// Expired keys are purged when they are next accessed, and by a background task that sleeps until the nearest
+/// deadline, so keys nobody reads again do not pile up. The task stops once the last clone is dropped.
+///
+/// The `Db` also holds the pub/sub channels, one `broadcast` channel per name that has subscribers, and one per pattern
+/// that has pattern subscribers.
+#[derive(Clone)]
+pub struct Db {
+    shared: Arc<Shared>,
+    // Signals the purge task to stop when the last clone goes away. The task holds its own `Arc<Shared>`, so `shared`
+    // alone never drops to zero references while it runs.
+    _purge_task: Arc<PurgeTaskGuard>,
+}
+
+/// Which `SET` goes ahead, see `Db::set_with`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetCondition {
+    Always,
+    /// `NX`: only if the key does not exist
+    IfAbsent,
+    /// `XX`: only if the key exists
+    IfPresent,
+}
+
+/// When a key written by `Db::set_with` expires
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Expiry {
+    Never,
+    At(Instant),
+    /// `KEEPTTL`: whenever the key it replaces would have
+    Keep,
+}
+
+/// Conditions for `Db::expire`, all of which must hold for the new deadline to be set
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExpireCondition {
+    /// `NX`: only if the key has no deadline yet
+    pub no_expiry: bool,
+    /// `XX`: only if the key has a deadline
+    pub has_expiry: bool,
+    /// `GT`: only if the new deadline is later than the current one. A key without a deadline lives forever, so this
+    /// never holds for it.
+    pub greater: bool,
+    /// `LT`: only if the new deadline is earlier than the current one, which always holds for a key without one
+    pub less: bool,
+}
+
+/// How many shards `Db::new` creates
+pub(crate) const DEFAULT_SHARDS: usize = 16;
+
+/// The most shards `Db::with_shards` accepts. Past a few times the number of cores more shards stop helping.
+pub const MAX_SHARDS: usize = 1024;
+
+struct Shared {
+    // Each shard is wrapped in a `Mutex` to allow sharing it between multiple tasks. The `Arc` around `Shared` is
+    // required to make it sendable between threads.
+
+    // Difference between std::sync::Mutex and tokio::sync::Mutex is that
+    // std::sync::Mutex is blocking the entire thread therefore all the tasks on that thread will be blocked
+
+    // tokio::sync::Mutex is only blocks the task that is trying to access the resource and not the entire thread, when the Mutex is locked, the task will be yield back to the scheduler and the scheduler will schedule other tasks to run.
+    // The locks are never held across an `.await`, so the std `Mutex` is the better fit here.
+    shards: Box<[Mutex<Shard>]>,
+    pub_sub: Mutex<PubSub>,
+    // Wakes the purge task when the nearest deadline moves earlier, or on shutdown
+    background_task: Notify,
+    shutdown: AtomicBool,
+}
+
+/// The keys that hash to one shard, see `shard_index`
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<String, Entry>,
+    // Deadlines ordered by time, so the next key to expire is always the first one. Keys without a deadline are not in
+    // here.
+    expirations: BTreeSet<(Instant, String)>,
+}
+
+/// The shards holding a set of keys, all locked at once. See `Db::lock`.
+struct Locked<'a> {
+    // Sorted by shard index
+    shards: Vec<(usize, MutexGuard<'a, Shard>)>,
+    count: usize,
+}
+
+#[derive(Default)]
+struct PubSub {
+    // Channels are created by their first subscriber, and removed once nobody listens any more
+    channels: HashMap<String, broadcast::Sender<Bytes>>,
+    patterns: Patterns,
+}
+
+/// A message published to a channel matching a pattern: the channel and the message
+pub type PatternMessage = (Bytes, Bytes);
+
+/// The patterns subscribed to, indexed by the literal prefix of each pattern, see `glob::literal_prefix`.
+///
+/// Only the patterns filed under a prefix of the channel name can match it, so a publish looks at those instead of
+/// every pattern. With hierarchical names like `news.tech` and patterns like `news.*` or `user:*`, that is a handful
+/// no matter how many patterns there are.
+#[derive(Default)]
+struct Patterns {
+    by_prefix: HashMap<Vec<u8>, HashMap<String, broadcast::Sender<PatternMessage>>>,
+    // How many patterns there are with a literal prefix of each length, so a publish only looks up prefixes of the
+    // channel name some pattern actually has
+    prefix_lens: BTreeMap<usize, usize>,
+}
+
+struct Entry {
+    data: Bytes,
+    expires_at: Option<Instant>,
+}
+
+struct PurgeTaskGuard {
+    shared: Arc<Shared>,
+}
+
+impl Db {
+    /// Create an empty store with the default number of shards and start its purge task. Must be called within a Tokio
+    /// runtime.
+    pub fn new() -> Db {
+        Db::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// Create an empty store split over `shards` shards, see `Db::new`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is 0 or more than `MAX_SHARDS`.
+    pub fn with_shards(shards: usize) -> Db {
+        assert!(shards > 0, "a Db needs at least one shard");
+        assert!(shards <= MAX_SHARDS, "a Db has at most {} shards", MAX_SHARDS);
+        let shared = Arc::new(Shared {
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+            pub_sub: Mutex::default(),
+            background_task: Notify::new(),
+            shutdown: AtomicBool::new(false),
+        });
+        tokio::spawn(purge_expired_keys(shared.clone()));
+
+        Db {
+            _purge_task: Arc::new(PurgeTaskGuard { shared: shared.clone() }),
+            shared,
+        }
+    }
+
+    /// The number of shards the keys are split over
+    pub fn shards(&self) -> usize {
+        self.shared.shards.len()
+    }
+
+    /// Get the value of a key, `None` if there is none
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        // `Bytes` is reference counted, so cloning the value does not copy it
+        self.shard(key).entry(key).map(|entry| entry.data.clone())
+    }
+
+    /// Get the values of several keys at once. They are read under one lock, so they are consistent with each other.
+    pub fn get_many(&self, keys: &[String]) -> Vec<Option<Bytes>> {
+        let mut locked = self.lock(keys.iter().map(String::as_str));
+        keys.iter().map(|key| locked.shard(key).entry(key).map(|entry| entry.data.clone())).collect()
+    }
+
+    /// Set the value of a key, replacing any previous value and its deadline
+    pub fn set(&self, key: String, value: Bytes) {
+        self.set_with(key, value, SetCondition::Always, Expiry::Never);
+    }
+
+    /// Set the value of a key if `condition` holds, with the given expiry.
+    ///
+    /// Returns whether the value was set, and the previous value.
+    pub fn set_with(&self, key: String, value: Bytes, condition: SetCondition, expiry: Expiry) -> (bool, Option<Bytes>) {
+        let mut shard = self.shard(&key);
+        let previous = shard.entry(&key).map(|entry| (entry.data.clone(), entry.expires_at));
+        let allowed = match condition {
+            SetCondition::Always => true,
+            SetCondition::IfAbsent => previous.is_none(),
+            SetCondition::IfPresent => previous.is_some(),
+        };
+        let (previous, previous_expiry) = match previous {
+            Some((data, expires_at)) => (Some(data), expires_at),
+            None => (None, None),
+        };
+        if !allowed {
+            return (false, previous);
+        }
+
+        let expires_at = match expiry {
+            Expiry::Never => None,
+            Expiry::At(when) => Some(when),
+            Expiry::Keep => previous_expiry,
+        };
+        shard.entries.insert(key.clone(), Entry { data: value, expires_at: previous_expiry });
+        let notify = shard.set_expiry(&key, expires_at);
+        drop(shard);
+
+        if notify {
+            self.shared.background_task.notify_one();
+        }
+        (true, previous)
+    }
+
+    /// Set several keys at once, removing their deadlines, if `condition` holds for every one of them. Either all keys
+    /// are set or none are, and no other command sees some of them set and others not.
+    ///
+    /// Returns whether the keys were set.
+    pub fn set_many(&self, pairs: Vec<(String, Bytes)>, condition: SetCondition) -> bool {
+        let mut locked = self.lock(pairs.iter().map(|(key, _)| key.as_str()));
+        let allowed = pairs.iter().all(|(key, _)| match condition {
+            SetCondition::Always => true,
+            SetCondition::IfAbsent => locked.shard(key).entry(key).is_none(),
+            SetCondition::IfPresent => locked.shard(key).entry(key).is_some(),
+        });
+        if !allowed {
+            return false;
+        }
+        for (key, value) in pairs {
+            let shard = locked.shard(&key);
+            shard.remove(&key);
+            shard.entries.insert(key, Entry { data: value, expires_at: None });
+        }
+        true
+    }
+
+    /// Delete keys, returning how many of them existed
+    pub fn delete(&self, keys: &[String]) -> usize {
+        let mut locked = self.lock(keys.iter().map(String::as_str));
+        keys.iter().filter(|key| locked.shard(key).take(key).is_some()).count()
+    }
+
+    /// Count how many of `keys` exist. A key given twice is counted twice, like Redis does.
+    pub fn exists(&self, keys: &[String]) -> usize {
+        let mut locked = self.lock(keys.iter().map(String::as_str));
+        keys.iter().filter(|key| locked.shard(key).entry(key).is_some()).count()
+    }
+
+    /// Get the value of a key and delete it
+    pub fn get_del(&self, key: &str) -> Option<Bytes> {
+        self.shard(key).take(key).map(|entry| entry.data)
+    }
+
+    /// Get the value of a key and change its deadline. `Expiry::Never` removes the deadline and `Expiry::Keep` leaves
+    /// it alone. A deadline that has already passed deletes the key, its value is still returned.
+    pub fn get_ex(&self, key: &str, expiry: Expiry) -> Option<Bytes> {
+        let mut shard = self.shard(key);
+        let data = shard.entry(key)?.data.clone();
+        let notify = match expiry {
+            Expiry::Keep => false,
+            Expiry::Never => shard.set_expiry(key, None),
+            Expiry::At(when) if when <= Instant::now() => {
+                shard.remove(key);
+                false
+            }
+            Expiry::At(when) => shard.set_expiry(key, Some(when)),
+        };
+        drop(shard);
+
+        if notify {
+            self.shared.background_task.notify_one();
+        }
+        Some(data)
+    }
+
+    /// Replace the value of a key with one computed from its current value, keeping its deadline.
+    ///
+    /// `f` gets the current value, `None` if the key does not exist. It returns the new value, or `None` to leave the
+    /// key as it is, along with the result passed back to the caller. The key's shard stays locked in between, so no
+    /// other client can change the key meanwhile. That is what makes `INCR` safe to use as a counter.
+    pub fn update<T>(&self, key: &str, f: impl FnOnce(Option<&Bytes>) -> (Option<Bytes>, T)) -> T {
+        let mut shard = self.shard(key);
+        let entry = shard.entry(key);
+        let (value, result) = f(entry.as_ref().map(|entry| &entry.data));
+        match (entry, value) {
+            (Some(entry), Some(value)) => entry.data = value,
+            (None, Some(value)) => {
+                shard.entries.insert(key.to_string(), Entry { data: value, expires_at: None });
+            }
+            (_, None) => {}
+        }
+        result
+    }
+
+    /// Give a key a new deadline if `condition` holds. A deadline that has already passed deletes the key.
+    ///
+    /// Returns whether the deadline was set, `false` if the key does not exist or the condition does not hold.
+    pub fn expire(&self, key: &str, when: Instant, condition: ExpireCondition) -> bool {
+        let mut shard = self.shard(key);
+        let Some(entry) = shard.entry(key) else {
+            return false;
+        };
+        let current = entry.expires_at;
+        let allowed = (!condition.no_expiry || current.is_none())
+            && (!condition.has_expiry || current.is_some())
+            && (!condition.greater || current.is_some_and(|current| when > current))
+            && (!condition.less || current.is_none_or(|current| when < current));
+        if !allowed {
+            return false;
+        }
+
+        if when <= Instant::now() {
+            shard.remove(key);
+            return true;
+        }
+        let notify = shard.set_expiry(key, Some(when));
+        drop(shard);
+
+        if notify {
+            self.shared.background_task.notify_one();
+        }
+        true
+    }
+
+    /// Remove the deadline of a key. Returns `false` if the key does not exist or has no deadline.
+    pub fn persist(&self, key: &str) -> bool {
+        let mut shard = self.shard(key);
+        match shard.entry(key) {
+            Some(entry) if entry.expires_at.is_some() => {
+                shard.set_expiry(key, None);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// How long a key has left to live: `None` if the key does not exist, `Some(None)` if it has no deadline
+    pub fn ttl(&self, key: &str) -> Option<Option<Duration>> {
+        let mut shard = self.shard(key);
+        let entry = shard.entry(key)?;
+        Some(entry.expires_at.map(|when| when.saturating_duration_since(Instant::now())))
+    }
+
+    /// Subscribe to a pub/sub channel, creating it if this is its first subscriber.
+    ///
+    /// A subscriber that falls more than 1024 messages behind misses the oldest ones, see `broadcast::Receiver`.
+    pub fn subscribe(&self, channel: String) -> broadcast::Receiver<Bytes> {
+        let mut pub_sub = self.shared.pub_sub.lock().unwrap();
+        match pub_sub.channels.get(&channel) {
+            Some(tx) => tx.subscribe(),
+            None => {
+                let (tx, rx) = broadcast::channel(1024);
+                pub_sub.channels.insert(channel, tx);
+                rx
+            }
+        }
+    }
+
+    /// Remove a channel once its last subscriber has dropped its receiver
+    pub fn release_channel(&self, channel: &str) {
+        let mut pub_sub = self.shared.pub_sub.lock().unwrap();
+        if pub_sub.channels.get(channel).is_some_and(|tx| tx.receiver_count() == 0) {
+            pub_sub.channels.remove(channel);
+        }
+    }
+
+    /// Subscribe to every channel matching a glob-style pattern, see `glob`
+    pub fn psubscribe(&self, pattern: String) -> broadcast::Receiver<PatternMessage> {
+        self.shared.pub_sub.lock().unwrap().patterns.subscribe(pattern)
+    }
+
+    /// Remove a pattern once its last subscriber has dropped its receiver
+    pub fn release_pattern(&self, pattern: &str) {
+        self.shared.pub_sub.lock().unwrap().patterns.release(pattern);
+    }
+
+    /// How many channels and how many patterns are registered, whether or not anybody still listens to them
+    #[cfg(test)]
+    pub(crate) fn registered(&self) -> (usize, usize) {
+        let pub_sub = self.shared.pub_sub.lock().unwrap();
+        (pub_sub.channels.len(), pub_sub.patterns.prefix_lens.values().sum())
+    }
+
+    /// Send a message to the subscribers of a channel and of the patterns matching it, returning how many received it
+    pub fn publish(&self, channel: &str, message: Bytes) -> usize {
+        let mut pub_sub = self.shared.pub_sub.lock().unwrap();
+        let mut receivers = 0;
+        if let Some(tx) = pub_sub.channels.get(channel) {
+            match tx.send(message.clone()) {
+                Ok(n) => receivers += n,
+                // Every subscriber has gone without releasing the channel, e.g. because its connection dropped
+                Err(_) => {
+                    pub_sub.channels.remove(channel);
+                }
+            }
+        }
+        receivers + pub_sub.patterns.publish(channel, message)
+    }
+
+    /// Lock the shard holding `key`
+    fn shard(&self, key: &str) -> MutexGuard<'_, Shard> {
+        let shards = &self.shared.shards;
+        shards[shard_index(key, shards.len())].lock().unwrap()
+    }
+
+    /// Lock every shard holding one of `keys`, for commands that work on several keys at once.
+    ///
+    /// The shards are always locked in ascending order. Two commands locking overlapping sets of shards then cannot
+    /// each hold a lock the other one waits for, so they cannot deadlock.
+    fn lock<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Locked<'_> {
+        let shards = &self.shared.shards;
+        let indices: BTreeSet<usize> = keys.into_iter().map(|key| shard_index(key, shards.len())).collect();
+        Locked {
+            shards: indices.into_iter().map(|i| (i, shards[i].lock().unwrap())).collect(),
+            count: shards.len(),
+        }
+    }
+}
+
+impl Default for Db {
+    fn default() -> Db {
+        Db::new()
+    }
+}
+
+impl Shared {
+    /// Remove every expired key, returning the deadline of the next one to expire
+    fn purge_expired_keys(&self) -> Option<Instant> {
+        if self.is_shutdown() {
+            return None;
+        }
+        let now = Instant::now();
+        // One shard at a time, so the purge never holds more than one lock
+        self.shards.iter().filter_map(|shard| shard.lock().unwrap().purge(now)).min()
+    }
+
+    fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::Acquire)
+    }
+}
+
+impl Shard {
+    /// The entry of a live key. An expired key is removed first, as if it was already gone.
+    fn entry(&mut self, key: &str) -> Option<&mut Entry> {
+        let expired = self.entries.get(key)?.expires_at.is_some_and(|when| when <= Instant::now());
+        if expired {
+            self.remove(key);
+            return None;
+        }
+        self.entries.get_mut(key)
+    }
+
+    /// Remove a live key, returning its entry
+    fn take(&mut self, key: &str) -> Option<Entry> {
+        self.entry(key)?;
+        self.remove(key)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        if let Some(when) = entry.expires_at {
+            self.expirations.remove(&(when, key.to_string()));
+        }
+        Some(entry)
+    }
+
+    /// Change the deadline of an existing key.
+    ///
+    /// Returns `true` if the new deadline is now the nearest one of the shard, in which case the purge task has to be
+    /// woken up, as it may have to sleep for less.
+    fn set_expiry(&mut self, key: &str, expires_at: Option<Instant>) -> bool {
+        let next = self.expirations.first().map(|(when, _)| *when);
+        let Some(entry) = self.entries.get_mut(key) else {
+            return false;
+        };
+        if let Some(when) = std::mem::replace(&mut entry.expires_at, expires_at) {
+            self.expirations.remove(&(when, key.to_string()));
+        }
+        match expires_at {
+            Some(when) => {
+                self.expirations.insert((when, key.to_string()));
+                next.is_none_or(|next| when < next)
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the keys that expired by `now`, returning the deadline of the next one to expire
+    fn purge(&mut self, now: Instant) -> Option<Instant> {
+        while let Some((when, key)) = self.expirations.first() {
+            if *when > now {
+                return Some(*when);
+            }
+            self.entries.remove(key);
+            self.expirations.pop_first();
+        }
+        None
+    }
+}
+
+impl Locked<'_> {
+    /// The locked shard holding `key`, which must be one of the keys passed to `Db::lock`
+    fn shard(&mut self, key: &str) -> &mut Shard {
+        let index = shard_index(key, self.count);
+        let position = self.shards.binary_search_by_key(&index, |(i, _)| *i).expect("shard of the key is not locked");
+        &mut self.shards[position].1
+    }
+}
+
+impl Patterns {
+    fn subscribe(&mut self, pattern: String) -> broadcast::Receiver<PatternMessage> {
+        let prefix = glob::literal_prefix(pattern.as_bytes()).to_vec();
+        let len = prefix.len();
+        let patterns = self.by_prefix.entry(prefix).or_default();
+        if let Some(tx) = patterns.get(&pattern) {
+            return tx.subscribe();
+        }
+        let (tx, rx) = broadcast::channel(1024);
+        patterns.insert(pattern, tx);
+        *self.prefix_lens.entry(len).or_default() += 1;
+        rx
+    }
+
+    /// Remove a pattern if nobody listens to it any more
+    fn release(&mut self, pattern: &str) {
+        let prefix = glob::literal_prefix(pattern.as_bytes());
+        let Some(patterns) = self.by_prefix.get_mut(prefix) else {
+            return;
+        };
+        if patterns.get(pattern).is_none_or(|tx| tx.receiver_count() > 0) {
+            return;
+        }
+        patterns.remove(pattern);
+        if patterns.is_empty() {
+            self.by_prefix.remove(prefix);
+        }
+        if let Some(count) = self.prefix_lens.get_mut(&prefix.len()) {
+            *count -= 1;
+            if *count == 0 {
+                self.prefix_lens.remove(&prefix.len());
+            }
+        }
+    }
+
+    /// Send a message to the subscribers of every pattern matching `channel`, returning how many received it
+    fn publish(&mut self, channel: &str, message: Bytes) -> usize {
+        let name = channel.as_bytes();
+        let channel = Bytes::copy_from_slice(name);
+        let mut receivers = 0;
+        let mut unused = vec![];
+        for &len in self.prefix_lens.range(..=name.len()).map(|(len, _)| len) {
+            let Some(patterns) = self.by_prefix.get(&name[..len]) else {
+                continue;
+            };
+            for (pattern, tx) in patterns {
+                if !glob::matches(pattern.as_bytes(), name) {
+                    continue;
+                }
+                match tx.send((channel.clone(), message.clone())) {
+                    Ok(n) => receivers += n,
+                    // Every subscriber has gone without releasing the pattern
+                    Err(_) => unused.push(pattern.clone()),
+                }
+            }
+        }
+        for pattern in unused {
+            self.release(&pattern);
+        }
+        receivers
+    }
+}
+
+impl Drop for PurgeTaskGuard {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Release);
+        // Wake the task so it sees the flag and exits
+        self.shared.background_task.notify_one();
+    }
+}
+
+/// Purge expired keys until the `Db` is dropped, sleeping until the nearest deadline in between
+async fn purge_expired_keys(shared: Arc<Shared>) {
+    while !shared.is_shutdown() {
+        match shared.purge_expired_keys() {
+            // Sleep until the next key expires, or until a nearer deadline is set
+            Some(when) => {
+                tokio::select! {
+                    _ = time::sleep_until(when) => {}
+                    _ = shared.background_task.notified() => {}
+                }
+            }
+            // No key has a deadline, sleep until one does
+            None => shared.background_task.notified().await,
+        }
+    }
+}
+
+/// The shard a key lives in, out of `shards`.
+///
+/// The hash is 64 bit FNV-1a rather than `DefaultHasher`, whose output may change between Rust releases, so a key
+/// always lands in the same shard.
+fn shard_index(key: &str, shards: usize) -> usize {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in key.as_bytes() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    (hash % shards as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The entries stored in every shard, including expired ones nobody has read or purged yet
+    fn stored(db: &Db) -> usize {
+        db.shared.shards.iter().map(|shard| shard.lock().unwrap().entries.len()).sum()
+    }
+
+    /// Let the purge task run
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_keys_are_gone_when_read() {
+        // A bare shard, without a purge task racing the read
+        let mut shard = Shard::default();
+        let when = Instant::now() + Duration::from_secs(1);
+        shard.entries.insert("k".to_string(), Entry { data: "v".into(), expires_at: None });
+        shard.set_expiry("k", Some(when));
+        assert!(shard.entry("k").is_some());
+
+        time::advance(Duration::from_millis(999)).await;
+        assert!(shard.entry("k").is_some());
+        time::advance(Duration::from_millis(1)).await;
+        assert!(shard.entry("k").is_none());
+        assert!(shard.entries.is_empty());
+        assert!(shard.expirations.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn purge_task_removes_keys_never_read() {
+        let db = Db::new();
+        let later = |secs| Expiry::At(Instant::now() + Duration::from_secs(secs));
+        db.set_with("a".to_string(), "1".into(), SetCondition::Always, later(2));
+        db.set_with("b".to_string(), "2".into(), SetCondition::Always, later(4));
+        db.set("c".to_string(), "3".into());
+        db.set("d".to_string(), "4".into());
+        // A nearer deadline than the one the task sleeps until wakes it up early
+        db.expire("d", Instant::now() + Duration::from_secs(1), ExpireCondition::default());
+        settle().await;
+        assert_eq!(stored(&db), 4);
+
+        time::advance(Duration::from_secs(1)).await;
+        settle().await;
+        assert_eq!(stored(&db), 3);
+        time::advance(Duration::from_secs(1)).await;
+        settle().await;
+        assert_eq!(stored(&db), 2);
+        time::advance(Duration::from_secs(2)).await;
+        settle().await;
+        assert_eq!(stored(&db), 1);
+        assert_eq!(db.get("c"), Some("3".into()));
+    }
+
+    #[test]
+    fn shard_index_is_fnv_1a() {
+        // The 64 bit FNV-1a hashes of "", "a" and "foobar" are 0xcbf29ce484222325, 0xaf63dc4c8601ec8c and
+        // 0x85944171f73967e8
+        assert_eq!(shard_index("", 16), 5);
+        assert_eq!(shard_index("a", 7), 5);
+        assert_eq!(shard_index("foobar", 1000), 968);
+        assert_eq!(shard_index("foobar", 1), 0);
+
+        // Keys spread over every shard
+        let mut counts = [0; 16];
+        for i in 0..1600 {
+            counts[shard_index(&format!("key:{}", i), 16)] += 1;
+        }
+        assert!(counts.iter().all(|&count| count > 50), "{:?}", counts);
+    }
+
+    #[tokio::test]
+    async fn commands_on_keys_in_different_shards() {
+        let db = Db::with_shards(8);
+        // One key from each shard
+        let mut keys = vec![String::new(); 8];
+        for i in 0.. {
+            let key = format!("key:{}", i);
+            let shard = shard_index(&key, 8);
+            if keys[shard].is_empty() {
+                keys[shard] = key;
+                if keys.iter().all(|key| !key.is_empty()) {
+                    break;
+                }
+            }
+        }
+        let value = |i: usize| Bytes::from(i.to_string());
+
+        let pairs: Vec<_> = keys.iter().take(4).enumerate().map(|(i, key)| (key.clone(), value(i))).collect();
+        assert!(db.set_many(pairs, SetCondition::Always));
+        let expected: Vec<_> = (0..8).map(|i| (i < 4).then(|| value(i))).collect();
+        assert_eq!(db.get_many(&keys), expected);
+        assert_eq!(db.exists(&keys), 4);
+
+        // All or nothing: one key in the last shards already exists
+        let pairs: Vec<_> = keys.iter().enumerate().skip(3).map(|(i, key)| (key.clone(), value(i + 10))).collect();
+        assert!(!db.set_many(pairs, SetCondition::IfAbsent));
+        assert_eq!(db.exists(&keys), 4);
+        let pairs: Vec<_> = keys.iter().enumerate().skip(4).map(|(i, key)| (key.clone(), value(i))).collect();
+        assert!(db.set_many(pairs, SetCondition::IfAbsent));
+        assert_eq!(db.get_many(&keys), (0..8).map(|i| Some(value(i))).collect::<Vec<_>>());
+
+        // A key named twice is counted twice by `exists`, but only deleted once
+        let twice = vec![keys[0].clone(), keys[7].clone(), keys[0].clone()];
+        assert_eq!(db.exists(&twice), 3);
+        assert_eq!(db.delete(&twice), 2);
+        assert_eq!(db.delete(&keys), 6);
+        assert_eq!(db.exists(&keys), 0);
+        assert_eq!(stored(&db), 0);
+    }
+}
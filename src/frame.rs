@@ -0,0 +1,845 @@
+use std::fmt;
+use std::io::Cursor;
+use bytes::{Buf, Bytes};
+
+/// The protocol version spoken on a connection.
+///
+/// Every connection starts out speaking RESP2. A client switches to RESP3 by sending `HELLO 3`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// A frame in the Redis protocol.
+///
+/// The first six variants are the RESP2 types. The rest only exist in RESP3. When they are written to a RESP2
+/// connection they are downgraded to the closest RESP2 shape, the same way Redis does it.
+/// https://github.com/redis/redis-specifications/blob/master/protocol/RESP3.md
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Bytes),
+    Null,
+    Array(Vec<Frame>),
+    Map(Vec<(Frame, Frame)>),
+    Set(Vec<Frame>),
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    // `format` is always three bytes, e.g. `txt` or `mkd`
+    Verbatim { format: String, data: Bytes },
+    // Out-of-band attributes attached to the frame that follows them
+    Attribute { attributes: Vec<(Frame, Frame)>, data: Box<Frame> },
+    Push(Vec<Frame>),
+}
+
+/// Caps on what a peer may send on a connection. A frame breaking any of them is a protocol error.
+///
+/// The defaults match Redis: a 1GB query buffer (`client-query-buffer-limit`) and 512MB bulk strings
+/// (`proto-max-bulk-len`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of bytes buffered while waiting for a frame to complete
+    pub max_buffer: usize,
+    /// Maximum length of a bulk string, blob error or verbatim string
+    pub max_bulk_len: usize,
+    /// Maximum number of elements in an array, set or push frame, or of pairs in a map or attribute frame
+    pub max_array_len: usize,
+    /// Maximum nesting depth of aggregate frames
+    pub max_depth: usize,
+    /// Maximum length of an inline command line
+    pub max_inline_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_buffer: 1024 * 1024 * 1024,
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_depth: 32,
+            max_inline_len: 64 * 1024,
+        }
+    }
+}
+
+/// Why a frame could not be parsed.
+///
+/// Offsets count bytes from the start of the frame (or of the line, for inline commands), pointing at where the
+/// problem was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Not enough data is available to parse a message
+    Incomplete,
+    /// A byte that is not allowed where it appears, e.g. an unknown frame type byte or a letter in a length
+    UnexpectedByte { byte: u8, offset: usize },
+    /// A negative length, or a length over the connection's `Limits`
+    InvalidLength { offset: usize },
+    /// A simple string, error or verbatim format that is not valid UTF-8
+    InvalidUtf8 { offset: usize },
+    /// A number that does not fit in 64 bits
+    Overflow { offset: usize },
+    /// Aggregates nested deeper than the connection's `Limits` allow
+    NestingTooDeep { offset: usize },
+    /// More than `Limits::max_buffer` bytes buffered without completing a frame
+    BufferFull { offset: usize },
+    /// An inline command line longer than `Limits::max_inline_len`
+    InlineTooLong { offset: usize },
+    /// An inline command with an unterminated quote, or a closing quote not followed by a blank
+    UnbalancedQuotes { offset: usize },
+}
+
+impl Frame {
+    /// Checks if an entire message can be decoded from `src`, rejecting it as soon as it breaks one of the `limits`.
+    ///
+    /// Lengths are checked when their header is seen, so a peer announcing a huge bulk string is turned away before
+    /// any of its payload is buffered.
+    pub fn check(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<(), Error> {
+        Frame::check_nested(src, limits, 0)
+    }
+
+    fn check_nested(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<(), Error> {
+        let offset = src.position() as usize;
+        if depth > limits.max_depth {
+            return Err(Error::NestingTooDeep { offset });
+        }
+        match get_u8(src)? {
+            // Simple string, simple error, double, boolean and big number are all single lines
+            b'+' | b'-' | b',' | b'#' | b'(' | b'_' => {
+                get_line(src)?;
+                Ok(())
+            }
+            b':' => {
+                get_decimal(src)?;
+                Ok(())
+            }
+            // Bulk string, blob error and verbatim string carry a length prefix
+            byte @ (b'$' | b'!' | b'=') => {
+                // `$-1\r\n` is the RESP2 null bulk string
+                let Some(len) = get_length(src, byte == b'$', limits.max_bulk_len)? else {
+                    return Ok(());
+                };
+                skip(src, len)?;
+                get_crlf(src)
+            }
+            // Array, set and push are followed by `len` frames
+            byte @ (b'*' | b'~' | b'>') => {
+                // `*-1\r\n` is the RESP2 null array
+                let len = get_length(src, byte == b'*', limits.max_array_len)?.unwrap_or(0);
+                for _ in 0..len {
+                    Frame::check_nested(src, limits, depth + 1)?;
+                }
+                Ok(())
+            }
+            // Map and attribute are followed by `len` key/value pairs
+            byte @ (b'%' | b'|') => {
+                let len = get_length(src, false, limits.max_array_len)?.unwrap_or(0);
+                for _ in 0..len * 2 {
+                    Frame::check_nested(src, limits, depth + 1)?;
+                }
+                if byte == b'|' {
+                    // The attributes are followed by the frame they describe
+                    Frame::check_nested(src, limits, depth)?;
+                }
+                Ok(())
+            }
+            byte => Err(Error::UnexpectedByte { byte, offset }),
+        }
+    }
+
+    /// Checks if the header of a bulk string or an aggregate can be read from `src`, returning its type byte and
+    /// length.
+    ///
+    /// Returns `None` for nulls and for every other kind of frame, which can only be read whole. A bulk string's length
+    /// is not held against `limits`, its payload is never buffered in full.
+    pub(crate) fn check_header(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<Option<(u8, usize)>, Error> {
+        let byte = get_u8(src)?;
+        let len = match byte {
+            b'$' => get_length(src, true, usize::MAX)?,
+            b'*' | b'~' | b'>' => get_length(src, byte == b'*', limits.max_array_len)?,
+            _ => return Ok(None),
+        };
+        Ok(len.map(|len| (byte, len)))
+    }
+
+    /// Returns `true` if `byte` starts a RESP frame. Anything else starts an inline command.
+    pub fn is_type_byte(byte: u8) -> bool {
+        matches!(
+            byte,
+            b'+' | b'-' | b':' | b'$' | b'*' | b'_' | b',' | b'#' | b'(' | b'!' | b'=' | b'%' | b'~' | b'>' | b'|'
+        )
+    }
+
+    /// Parse an inline command, e.g. `SET foo "bar baz"` typed into `nc` or `telnet`.
+    ///
+    /// `line` is a single line without its `\n`. The arguments are split the way Redis splits them: on whitespace,
+    /// with double quoted arguments supporting `\n`, `\xff` style escapes and single quoted arguments taken literally.
+    /// The command is returned as an array of bulk strings, the same shape a RESP client sends. A blank line yields
+    /// `None`.
+    pub fn parse_inline(line: &[u8]) -> Result<Option<Frame>, Error> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let args = split_args(line).map_err(|offset| Error::UnbalancedQuotes { offset })?;
+        if args.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Frame::Array(args.into_iter().map(Frame::Bulk).collect())))
+    }
+
+    /// The message has already been validated with `check`.
+    ///
+    /// `src` holds the frame's bytes as a `Bytes`, so bulk payloads are sliced out of it rather than copied: they
+    /// share the allocation the frame was read into.
+    pub fn parse(src: &mut Cursor<Bytes>) -> Result<Frame, Error> {
+        let offset = src.position() as usize;
+        match get_u8(src)? {
+            b'+' => Ok(Frame::Simple(get_string(src)?)),
+            b'-' => Ok(Frame::Error(get_string(src)?)),
+            b':' => Ok(Frame::Integer(get_decimal(src)?)),
+            b'$' => match get_blob(src, true)? {
+                Some(data) => Ok(Frame::Bulk(data)),
+                None => Ok(Frame::Null),
+            },
+            b'*' => match get_length(src, true, usize::MAX)? {
+                Some(len) => Ok(Frame::Array(get_frames(src, len)?)),
+                // `*-1\r\n` is the RESP2 null array
+                None => Ok(Frame::Null),
+            },
+            b'_' => {
+                let start = src.position() as usize;
+                if let Some(&byte) = get_line(src)?.first() {
+                    return Err(Error::UnexpectedByte { byte, offset: start });
+                }
+                Ok(Frame::Null)
+            }
+            b',' => {
+                let start = src.position() as usize;
+                let line = get_string(src)?;
+                let val = match line.as_str() {
+                    "inf" => f64::INFINITY,
+                    "-inf" => f64::NEG_INFINITY,
+                    "nan" => f64::NAN,
+                    _ => line.parse().map_err(|_| {
+                        // Point at the first byte that cannot appear in a double, or at the end of a truncated one
+                        let bad = line
+                            .bytes()
+                            .position(|b| !matches!(b, b'0'..=b'9' | b'+' | b'-' | b'.' | b'e' | b'E'))
+                            .unwrap_or(line.len());
+                        let byte = line.as_bytes().get(bad).copied().unwrap_or(b'\r');
+                        Error::UnexpectedByte { byte, offset: start + bad }
+                    })?,
+                };
+                Ok(Frame::Double(val))
+            }
+            b'#' => {
+                let start = src.position() as usize;
+                match get_line(src)? {
+                    b"t" => Ok(Frame::Boolean(true)),
+                    b"f" => Ok(Frame::Boolean(false)),
+                    line => {
+                        // Either the wrong letter, or something after a valid one
+                        let bad = if matches!(line.first(), Some(b't' | b'f')) { 1 } else { 0 };
+                        let byte = line.get(bad).copied().unwrap_or(b'\r');
+                        Err(Error::UnexpectedByte { byte, offset: start + bad })
+                    }
+                }
+            }
+            b'(' => {
+                let start = src.position() as usize;
+                let line = get_string(src)?;
+                let sign = usize::from(line.starts_with('-'));
+                if line.len() == sign {
+                    return Err(Error::UnexpectedByte { byte: b'\r', offset: start + sign });
+                }
+                if let Some(bad) = line.bytes().skip(sign).position(|b| !b.is_ascii_digit()) {
+                    let bad = bad + sign;
+                    return Err(Error::UnexpectedByte { byte: line.as_bytes()[bad], offset: start + bad });
+                }
+                Ok(Frame::BigNumber(line))
+            }
+            b'!' => {
+                let start = src.position() as usize;
+                let data = get_blob(src, false)?.expect("blob errors are never null");
+                let string = String::from_utf8(data.to_vec()).map_err(|e| Error::InvalidUtf8 {
+                    offset: start + e.utf8_error().valid_up_to(),
+                })?;
+                Ok(Frame::Error(string))
+            }
+            b'=' => {
+                let start = src.position() as usize;
+                let data = get_blob(src, false)?.expect("verbatim strings are never null");
+                // The payload starts with a three byte format followed by `:`
+                let payload = src.position() as usize - data.len() - 2;
+                if data.len() < 4 {
+                    return Err(Error::InvalidLength { offset: start });
+                }
+                if data[3] != b':' {
+                    return Err(Error::UnexpectedByte { byte: data[3], offset: payload + 3 });
+                }
+                let format = String::from_utf8(data[..3].to_vec()).map_err(|e| Error::InvalidUtf8 {
+                    offset: payload + e.utf8_error().valid_up_to(),
+                })?;
+                Ok(Frame::Verbatim { format, data: data.slice(4..) })
+            }
+            b'%' => {
+                let len = get_length(src, false, usize::MAX)?.unwrap_or(0);
+                Ok(Frame::Map(get_pairs(src, len)?))
+            }
+            b'~' => {
+                let len = get_length(src, false, usize::MAX)?.unwrap_or(0);
+                Ok(Frame::Set(get_frames(src, len)?))
+            }
+            b'>' => {
+                let len = get_length(src, false, usize::MAX)?.unwrap_or(0);
+                Ok(Frame::Push(get_frames(src, len)?))
+            }
+            b'|' => {
+                let len = get_length(src, false, usize::MAX)?.unwrap_or(0);
+                let attributes = get_pairs(src, len)?;
+                let data = Box::new(Frame::parse(src)?);
+                Ok(Frame::Attribute { attributes, data })
+            }
+            byte => Err(Error::UnexpectedByte { byte, offset }),
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Frame::Simple(response) => response.fmt(fmt),
+            Frame::Error(msg) => write!(fmt, "error: {}", msg),
+            Frame::Integer(num) => num.fmt(fmt),
+            Frame::Bulk(msg) | Frame::Verbatim { data: msg, .. } => match std::str::from_utf8(msg) {
+                Ok(string) => string.fmt(fmt),
+                Err(_) => write!(fmt, "{:?}", msg),
+            },
+            Frame::Null => "(nil)".fmt(fmt),
+            Frame::Array(parts) | Frame::Set(parts) | Frame::Push(parts) => {
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    part.fmt(fmt)?;
+                }
+                Ok(())
+            }
+            Frame::Map(pairs) => {
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    write!(fmt, "{} => {}", key, value)?;
+                }
+                Ok(())
+            }
+            Frame::Double(val) => val.fmt(fmt),
+            Frame::Boolean(val) => val.fmt(fmt),
+            Frame::BigNumber(val) => val.fmt(fmt),
+            Frame::Attribute { data, .. } => data.fmt(fmt),
+        }
+    }
+}
+
+/// `val` the way Redis writes a double: the shortest digits that parse back to it, laid out like `%.17g`. Very large
+/// and very small values get an exponent, `1e+300` rather than 301 digits.
+pub(crate) fn format_double(val: f64) -> String {
+    if val.is_nan() {
+        "nan".to_string()
+    } else if val.is_infinite() {
+        if val > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        Decimal::from(val).to_string()
+    }
+}
+
+/// A number as its decimal digits, most significant first, times `10^exp`
+pub(crate) struct Decimal {
+    pub(crate) negative: bool,
+    pub(crate) digits: Vec<u8>,
+    pub(crate) exp: i32,
+}
+
+impl Decimal {
+    /// The shortest decimal that parses back to `n`
+    pub(crate) fn from(n: f64) -> Decimal {
+        // `{:e}` prints the shortest digits that round-trip, e.g. `1.25e-3`
+        let text = format!("{:e}", n.abs());
+        let (mantissa, exp) = text.split_once('e').expect("`{:e}` always has an exponent");
+        let fraction = mantissa.split_once('.').map_or(0, |(_, fraction)| fraction.len());
+        Decimal {
+            negative: n.is_sign_negative(),
+            digits: mantissa.bytes().filter(u8::is_ascii_digit).map(|d| d - b'0').collect(),
+            exp: exp.parse::<i32>().expect("`{:e}` exponents are integers") - fraction as i32,
+        }
+    }
+
+    /// The digits of the same number written with the exponent `exp`, which is at most `self.exp`
+    pub(crate) fn shift_to(&self, exp: i32) -> Vec<u8> {
+        let mut digits = self.digits.clone();
+        digits.resize(digits.len() + (self.exp - exp) as usize, 0);
+        digits
+    }
+}
+
+/// Formats a rounded, trimmed `Decimal` the way `%g` does
+impl fmt::Display for Decimal {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let digits: String = self.digits.iter().map(|d| char::from(b'0' + d)).collect();
+        if self.negative {
+            "-".fmt(fmt)?;
+        }
+        // The exponent in scientific notation, `d.ddd * 10^sci`
+        let sci = self.exp + digits.len() as i32 - 1;
+        if !(-4..17).contains(&sci) {
+            let (first, rest) = digits.split_at(1);
+            let point = if rest.is_empty() { "" } else { "." };
+            let sign = if sci < 0 { '-' } else { '+' };
+            return write!(fmt, "{}{}{}e{}{:02}", first, point, rest, sign, sci.abs());
+        }
+        if self.exp >= 0 {
+            write!(fmt, "{}{}", digits, "0".repeat(self.exp as usize))
+        } else if sci >= 0 {
+            let (whole, fraction) = digits.split_at(sci as usize + 1);
+            write!(fmt, "{}.{}", whole, fraction)
+        } else {
+            write!(fmt, "0.{}{}", "0".repeat((-sci - 1) as usize), digits)
+        }
+    }
+}
+
+fn get_u8<T: AsRef<[u8]>>(src: &mut Cursor<T>) -> Result<u8, Error> {
+    if !src.has_remaining() {
+        return Err(Error::Incomplete);
+    }
+    Ok(src.get_u8())
+}
+
+fn skip<T: AsRef<[u8]>>(src: &mut Cursor<T>, n: usize) -> Result<(), Error> {
+    if src.remaining() < n {
+        return Err(Error::Incomplete);
+    }
+    src.advance(n);
+    Ok(())
+}
+
+/// Read the `\r\n` ending a blob
+fn get_crlf<T: AsRef<[u8]>>(src: &mut Cursor<T>) -> Result<(), Error> {
+    for expected in *b"\r\n" {
+        let offset = src.position() as usize;
+        match get_u8(src)? {
+            byte if byte == expected => {}
+            byte => return Err(Error::UnexpectedByte { byte, offset }),
+        }
+    }
+    Ok(())
+}
+
+/// Read a new-line terminated signed decimal
+fn get_decimal<T: AsRef<[u8]>>(src: &mut Cursor<T>) -> Result<i64, Error> {
+    let start = src.position() as usize;
+    let line = get_line(src)?;
+
+    let (negative, digits) = match line.split_first() {
+        Some((b'-', digits)) => (true, digits),
+        _ => (false, line),
+    };
+    let first = start + usize::from(negative);
+    if digits.is_empty() {
+        // The line ended before any digit, point at its `\r`
+        return Err(Error::UnexpectedByte { byte: b'\r', offset: first });
+    }
+
+    let mut val: i64 = 0;
+    for (i, &byte) in digits.iter().enumerate() {
+        if !byte.is_ascii_digit() {
+            return Err(Error::UnexpectedByte { byte, offset: first + i });
+        }
+        let digit = (byte - b'0') as i64;
+        // Accumulate towards the sign so that `i64::MIN` can be represented
+        val = val
+            .checked_mul(10)
+            .and_then(|val| if negative { val.checked_sub(digit) } else { val.checked_add(digit) })
+            .ok_or(Error::Overflow { offset: start })?;
+    }
+    Ok(val)
+}
+
+/// Read the length header of a blob or aggregate frame.
+///
+/// `-1` is a null and yields `None` when `nullable` is set. Any other negative length, or one over `max`, is rejected.
+fn get_length<T: AsRef<[u8]>>(src: &mut Cursor<T>, nullable: bool, max: usize) -> Result<Option<usize>, Error> {
+    let offset = src.position() as usize;
+    match get_decimal(src)? {
+        -1 if nullable => Ok(None),
+        len if len < 0 || len as u64 > max as u64 => Err(Error::InvalidLength { offset }),
+        len => Ok(Some(len as usize)),
+    }
+}
+
+/// Read a new-line terminated UTF-8 string
+fn get_string(src: &mut Cursor<Bytes>) -> Result<String, Error> {
+    let start = src.position() as usize;
+    let line = get_line(src)?.to_vec();
+    String::from_utf8(line).map_err(|e| Error::InvalidUtf8 {
+        offset: start + e.utf8_error().valid_up_to(),
+    })
+}
+
+/// Read a length prefixed blob. A null blob, only allowed if `nullable` is set, yields `None`.
+///
+/// The blob is a slice of `src`, no bytes are copied.
+fn get_blob(src: &mut Cursor<Bytes>, nullable: bool) -> Result<Option<Bytes>, Error> {
+    let Some(len) = get_length(src, nullable, usize::MAX)? else {
+        return Ok(None);
+    };
+    if src.remaining() < len + 2 {
+        return Err(Error::Incomplete);
+    }
+    let start = src.position() as usize;
+    let data = src.get_ref().slice(start..start + len);
+    skip(src, len)?;
+    get_crlf(src)?;
+    Ok(Some(data))
+}
+
+/// Parse `len` consecutive frames
+fn get_frames(src: &mut Cursor<Bytes>, len: usize) -> Result<Vec<Frame>, Error> {
+    let mut out = Vec::with_capacity(len);
+    for _ in 0..len {
+        out.push(Frame::parse(src)?);
+    }
+    Ok(out)
+}
+
+/// Parse `len` consecutive key/value pairs
+fn get_pairs(src: &mut Cursor<Bytes>, len: usize) -> Result<Vec<(Frame, Frame)>, Error> {
+    let mut out = Vec::with_capacity(len);
+    for _ in 0..len {
+        let key = Frame::parse(src)?;
+        let value = Frame::parse(src)?;
+        out.push((key, value));
+    }
+    Ok(out)
+}
+
+/// Split an inline command line into arguments, following Redis' `sdssplitargs`.
+///
+/// Fails with the offset of the offending quote if the quotes are unbalanced or a closing quote is not followed by
+/// whitespace.
+pub(crate) fn split_args(line: &[u8]) -> Result<Vec<Bytes>, usize> {
+    let mut args = vec![];
+    let mut i = 0;
+
+    loop {
+        // Skip blanks
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(args);
+        }
+
+        let mut arg = vec![];
+        // `Some(quote)` while inside a quoted section, along with where it was opened
+        let mut quote = None;
+        let mut opened = 0;
+        loop {
+            match (quote, line.get(i).copied()) {
+                // The line ended inside quotes
+                (Some(_), None) => return Err(opened),
+                // The argument ends at the next blank or the end of the line
+                (None, None) => break,
+                (None, Some(c)) if c.is_ascii_whitespace() => break,
+                (None, Some(c @ (b'"' | b'\''))) => {
+                    quote = Some(c);
+                    opened = i;
+                }
+                (None, Some(c)) => arg.push(c),
+                (Some(b'"'), Some(b'\\')) if i + 3 < line.len()
+                    && line[i + 1] == b'x'
+                    && line[i + 2].is_ascii_hexdigit()
+                    && line[i + 3].is_ascii_hexdigit() =>
+                {
+                    let hex = std::str::from_utf8(&line[i + 2..i + 4]).expect("hex digits are ASCII");
+                    arg.push(u8::from_str_radix(hex, 16).expect("checked to be hex digits"));
+                    i += 3;
+                }
+                (Some(b'"'), Some(b'\\')) if i + 1 < line.len() => {
+                    i += 1;
+                    arg.push(match line[i] {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'b' => 0x08,
+                        b'a' => 0x07,
+                        c => c,
+                    });
+                }
+                (Some(b'\''), Some(b'\\')) if line.get(i + 1) == Some(&b'\'') => {
+                    i += 1;
+                    arg.push(b'\'');
+                }
+                (Some(q), Some(c)) if c == q => {
+                    // A closing quote must be followed by a blank or the end of the line
+                    if line.get(i + 1).is_some_and(|c| !c.is_ascii_whitespace()) {
+                        return Err(i);
+                    }
+                    i += 1;
+                    break;
+                }
+                (Some(_), Some(c)) => arg.push(c),
+            }
+            i += 1;
+        }
+        args.push(Bytes::from(arg));
+    }
+}
+
+/// Find a line
+fn get_line<T: AsRef<[u8]>>(src: &mut Cursor<T>) -> Result<&[u8], Error> {
+    // Scan the bytes directly
+    let start = src.position() as usize;
+    let len = src.get_ref().as_ref().len();
+
+    // Scan to the second to last byte
+    for i in start..len.saturating_sub(1) {
+        let buf = src.get_ref().as_ref();
+        if buf[i] == b'\r' && buf[i + 1] == b'\n' {
+            // We found a line, update the position to be *after* the \n
+            src.set_position((i + 2) as u64);
+            return Ok(&src.get_ref().as_ref()[start..i]);
+        }
+    }
+
+    Err(Error::Incomplete)
+}
+
+impl std::error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Incomplete => "stream ended early".fmt(fmt),
+            Error::UnexpectedByte { byte, offset } => write!(
+                fmt,
+                "Protocol error: unexpected byte '{}' at offset {}",
+                byte.escape_ascii(),
+                offset
+            ),
+            Error::InvalidLength { offset } => write!(fmt, "Protocol error: invalid length at offset {}", offset),
+            Error::InvalidUtf8 { offset } => write!(fmt, "Protocol error: invalid UTF-8 at offset {}", offset),
+            Error::Overflow { offset } => write!(fmt, "Protocol error: number out of range at offset {}", offset),
+            Error::NestingTooDeep { offset } => {
+                write!(fmt, "Protocol error: aggregates nested too deeply at offset {}", offset)
+            }
+            Error::BufferFull { offset } => {
+                write!(fmt, "Protocol error: query buffer limit exceeded at offset {}", offset)
+            }
+            Error::InlineTooLong { offset } => {
+                write!(fmt, "Protocol error: too big inline request at offset {}", offset)
+            }
+            Error::UnbalancedQuotes { offset } => {
+                write!(fmt, "Protocol error: unbalanced quotes in request at offset {}", offset)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inline(line: &[u8]) -> Result<Option<Frame>, Error> {
+        Frame::parse_inline(line)
+    }
+
+    fn check(src: &[u8], limits: &Limits) -> Result<(), Error> {
+        Frame::check(&mut Cursor::new(src), limits)
+    }
+
+    fn parse(src: &[u8]) -> Result<Frame, Error> {
+        Frame::parse(&mut Cursor::new(Bytes::copy_from_slice(src)))
+    }
+
+    fn encode(frame: &Frame, protocol: Protocol) -> Vec<u8> {
+        let mut dst = vec![];
+        crate::connection_bytes::encode(frame, protocol, &mut dst);
+        dst
+    }
+
+    /// `src` parses to `frame`, which encodes back to `src` on RESP3
+    fn roundtrip(src: &[u8], frame: Frame) {
+        assert_eq!(check(src, &Limits::default()), Ok(()), "{}", src.escape_ascii());
+        assert_eq!(parse(src), Ok(frame.clone()), "{}", src.escape_ascii());
+        assert_eq!(encode(&frame, Protocol::Resp3), src, "{}", src.escape_ascii());
+    }
+
+    fn command(args: &[&[u8]]) -> Option<Frame> {
+        Some(Frame::Array(args.iter().map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg))).collect()))
+    }
+
+    #[test]
+    fn inline_splits_on_blanks() {
+        assert_eq!(inline(b"SET foo  bar\r"), Ok(command(&[b"SET", b"foo", b"bar"])));
+        assert_eq!(inline(b"  \t PING"), Ok(command(&[b"PING"])));
+        assert_eq!(inline(b" \r"), Ok(None));
+        assert_eq!(inline(b""), Ok(None));
+    }
+
+    #[test]
+    fn inline_quoted_arguments() {
+        assert_eq!(inline(br#"SET "foo bar" 'baz qux'"#), Ok(command(&[b"SET", b"foo bar", b"baz qux"])));
+        assert_eq!(inline(br#"SET k"ey" v"#), Ok(command(&[b"SET", b"key", b"v"])));
+        assert_eq!(inline(br#"SET "" ''"#), Ok(command(&[b"SET", b"", b""])));
+        // Single quotes are literal apart from an escaped single quote
+        assert_eq!(inline(br#"SET 'a\n\'b' v"#), Ok(command(&[b"SET", b"a\\n'b", b"v"])));
+    }
+
+    #[test]
+    fn inline_escapes() {
+        assert_eq!(inline(br#"SET k "a\nb\r\t\\\"""#), Ok(command(&[b"SET", b"k", b"a\nb\r\t\\\""])));
+        assert_eq!(inline(br#"SET k "\x00\xff\x7E""#), Ok(command(&[b"SET", b"k", b"\x00\xff\x7e"])));
+        // An incomplete or invalid hex escape is taken as an escaped `x`
+        assert_eq!(inline(br#"SET k "\xg1" "\x1""#), Ok(command(&[b"SET", b"k", b"xg1", b"x1"])));
+        // Escapes are only recognised inside double quotes
+        assert_eq!(inline(br#"SET k \x41"#), Ok(command(&[b"SET", b"k", b"\\x41"])));
+    }
+
+    #[test]
+    fn inline_unbalanced_quotes() {
+        assert_eq!(inline(br#"SET k "abc"#), Err(Error::UnbalancedQuotes { offset: 6 }));
+        assert_eq!(inline(br#"SET k 'abc"#), Err(Error::UnbalancedQuotes { offset: 6 }));
+        // A closing quote must be followed by a blank
+        assert_eq!(inline(br#"SET "k"v x"#), Err(Error::UnbalancedQuotes { offset: 6 }));
+        assert_eq!(inline(br#"SET k "a\""#), Err(Error::UnbalancedQuotes { offset: 6 }));
+    }
+
+    #[test]
+    fn bulk_length_limit() {
+        let limits = Limits { max_bulk_len: 5, ..Limits::default() };
+        assert_eq!(check(b"$5\r\nhello\r\n", &limits), Ok(()));
+        assert_eq!(check(b"$6\r\n", &limits), Err(Error::InvalidLength { offset: 1 }));
+        assert_eq!(check(b"*2\r\n$1\r\na\r\n!6\r\n", &limits), Err(Error::InvalidLength { offset: 12 }));
+        assert_eq!(check(b"$-2\r\n", &limits), Err(Error::InvalidLength { offset: 1 }));
+    }
+
+    #[test]
+    fn array_length_limit() {
+        let limits = Limits { max_array_len: 2, ..Limits::default() };
+        assert_eq!(check(b"*2\r\n:1\r\n:2\r\n", &limits), Ok(()));
+        assert_eq!(check(b"*3\r\n", &limits), Err(Error::InvalidLength { offset: 1 }));
+        assert_eq!(check(b"*1\r\n~3\r\n", &limits), Err(Error::InvalidLength { offset: 5 }));
+        // Maps count pairs, not elements
+        assert_eq!(check(b"%2\r\n:1\r\n:2\r\n:3\r\n:4\r\n", &limits), Ok(()));
+        assert_eq!(check(b"%3\r\n", &limits), Err(Error::InvalidLength { offset: 1 }));
+    }
+
+    #[test]
+    fn depth_limit() {
+        let limits = Limits { max_depth: 2, ..Limits::default() };
+        assert_eq!(check(b"*1\r\n*1\r\n:1\r\n", &limits), Ok(()));
+        assert_eq!(check(b"*1\r\n*1\r\n*1\r\n:1\r\n", &limits), Err(Error::NestingTooDeep { offset: 12 }));
+        assert_eq!(check(b"%1\r\n:1\r\n>1\r\n~1\r\n", &limits), Err(Error::NestingTooDeep { offset: 16 }));
+    }
+
+    #[test]
+    fn blob_terminator() {
+        let limits = Limits::default();
+        assert_eq!(check(b"$3\r\nabc\r\n", &limits), Ok(()));
+        assert_eq!(check(b"$3\r\nabcXY", &limits), Err(Error::UnexpectedByte { byte: b'X', offset: 7 }));
+        assert_eq!(check(b"$3\r\nabc\rY", &limits), Err(Error::UnexpectedByte { byte: b'Y', offset: 8 }));
+        assert_eq!(check(b"*1\r\n!3\r\nabcd\r\n", &limits), Err(Error::UnexpectedByte { byte: b'd', offset: 11 }));
+        assert_eq!(check(b"$3\r\nabc\r", &limits), Err(Error::Incomplete));
+        assert_eq!(parse(b"$3\r\nabcXY"), Err(Error::UnexpectedByte { byte: b'X', offset: 7 }));
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_problem() {
+        assert_eq!(parse(b"?\r\n"), Err(Error::UnexpectedByte { byte: b'?', offset: 0 }));
+        assert_eq!(parse(b":12a\r\n"), Err(Error::UnexpectedByte { byte: b'a', offset: 3 }));
+        assert_eq!(parse(b":-\r\n"), Err(Error::UnexpectedByte { byte: b'\r', offset: 2 }));
+        assert_eq!(parse(b":9223372036854775808\r\n"), Err(Error::Overflow { offset: 1 }));
+        assert_eq!(parse(b":-9223372036854775808\r\n"), Ok(Frame::Integer(i64::MIN)));
+        assert_eq!(parse(b"+ok\xff\r\n"), Err(Error::InvalidUtf8 { offset: 3 }));
+        assert_eq!(parse(b"#x\r\n"), Err(Error::UnexpectedByte { byte: b'x', offset: 1 }));
+        assert_eq!(parse(b"=3\r\ntxt\r\n"), Err(Error::InvalidLength { offset: 1 }));
+    }
+
+    #[test]
+    fn resp3_aggregates_roundtrip() {
+        let pair = |key: &str, value| (Frame::Simple(key.into()), value);
+        roundtrip(
+            b"%2\r\n+first\r\n:1\r\n+second\r\n#t\r\n",
+            Frame::Map(vec![pair("first", Frame::Integer(1)), pair("second", Frame::Boolean(true))]),
+        );
+        roundtrip(b"%0\r\n", Frame::Map(vec![]));
+        roundtrip(
+            b"~3\r\n$3\r\nfoo\r\n:2\r\n_\r\n",
+            Frame::Set(vec![Frame::Bulk("foo".into()), Frame::Integer(2), Frame::Null]),
+        );
+        roundtrip(
+            b">3\r\n$7\r\nmessage\r\n$7\r\nchannel\r\n$5\r\nhello\r\n",
+            Frame::Push(vec![
+                Frame::Bulk("message".into()),
+                Frame::Bulk("channel".into()),
+                Frame::Bulk("hello".into()),
+            ]),
+        );
+        // Attributes are attached to the frame that follows them
+        let popularity = Frame::Map(vec![(Frame::Bulk("a".into()), Frame::Double(0.1923))]);
+        roundtrip(
+            b"|1\r\n+key-popularity\r\n%1\r\n$1\r\na\r\n,0.1923\r\n*2\r\n:2039123\r\n:9543892\r\n",
+            Frame::Attribute {
+                attributes: vec![pair("key-popularity", popularity)],
+                data: Box::new(Frame::Array(vec![Frame::Integer(2039123), Frame::Integer(9543892)])),
+            },
+        );
+        // Nested in each other
+        roundtrip(
+            b">2\r\n%1\r\n+set\r\n~1\r\n(7\r\n=7\r\ntxt:abc\r\n",
+            Frame::Push(vec![
+                Frame::Map(vec![pair("set", Frame::Set(vec![Frame::BigNumber("7".into())]))]),
+                Frame::Verbatim { format: "txt".into(), data: "abc".into() },
+            ]),
+        );
+    }
+
+    #[test]
+    fn resp3_scalars_roundtrip() {
+        let big = "3492890328409238509324850943850943825024385";
+        roundtrip(format!("({}\r\n", big).as_bytes(), Frame::BigNumber(big.into()));
+        roundtrip(b"(-12\r\n", Frame::BigNumber("-12".into()));
+        roundtrip(b"=15\r\ntxt:Some string\r\n", Frame::Verbatim { format: "txt".into(), data: "Some string".into() });
+        roundtrip(b"=6\r\nmkd:\r\n\r\n", Frame::Verbatim { format: "mkd".into(), data: "\r\n".into() });
+        roundtrip(b",2.5\r\n", Frame::Double(2.5));
+        roundtrip(b",-0.125\r\n", Frame::Double(-0.125));
+        roundtrip(b",10\r\n", Frame::Double(10.0));
+        roundtrip(b",0.1\r\n", Frame::Double(0.1));
+        roundtrip(b",inf\r\n", Frame::Double(f64::INFINITY));
+        roundtrip(b",-inf\r\n", Frame::Double(f64::NEG_INFINITY));
+        // NaN is not equal to itself
+        assert!(matches!(parse(b",nan\r\n"), Ok(Frame::Double(val)) if val.is_nan()));
+        assert_eq!(encode(&Frame::Double(f64::NAN), Protocol::Resp3), b",nan\r\n");
+    }
+
+    #[test]
+    fn doubles_with_exponents() {
+        // Written like `%.17g`, with an exponent once the digits would be too many or too far from the point
+        roundtrip(b",1e+300\r\n", Frame::Double(1e300));
+        roundtrip(b",-1.7976931348623157e+308\r\n", Frame::Double(f64::MIN));
+        roundtrip(b",1.5e+17\r\n", Frame::Double(1.5e17));
+        roundtrip(b",10000000000000000\r\n", Frame::Double(1e16));
+        roundtrip(b",0.0001\r\n", Frame::Double(1e-4));
+        roundtrip(b",1e-05\r\n", Frame::Double(1e-5));
+        roundtrip(b",5e-324\r\n", Frame::Double(5e-324));
+        // Any form Rust parses is accepted
+        assert_eq!(parse(b",1E300\r\n"), Ok(Frame::Double(1e300)));
+        assert_eq!(parse(b",+.5e1\r\n"), Ok(Frame::Double(5.0)));
+        // A bulk string on RESP2
+        assert_eq!(encode(&Frame::Double(1e300), Protocol::Resp2), b"$6\r\n1e+300\r\n");
+    }
+}
@@ -0,0 +1,211 @@
+//! Redis' glob-style patterns, as used by `PSUBSCRIBE`.
+//!
+//! * `?` matches any single byte
+//! * `*` matches any number of bytes, including none
+//! * `[abc]`, `[a-z]` and `[^a-z]` match a single byte in, or not in, the class
+//! * `\` matches the byte after it literally, e.g. `\*`
+//!
+//! Patterns and strings are compared byte by byte, the same way Redis' `stringmatchlen` does it, including its quirks:
+//! an unterminated class such as `[abc` ends with the pattern, and a range may be written backwards, `[z-a]`.
+//! Like Redis, a pattern nesting more than `MAX_NESTING` stars matches nothing, so matching never recurses deeper.
+
+/// The longest pattern `PSUBSCRIBE` accepts
+pub const MAX_PATTERN_LEN: usize = 4096;
+
+/// How many stars deep `matches` backtracks before giving up on a match, Redis' limit
+const MAX_NESTING: usize = 1000;
+
+/// Whether `string` matches `pattern`
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let mut skip_longer = false;
+    matches_from(pattern, string, &mut skip_longer, 0)
+}
+
+/// The literal bytes a pattern starts with, before its first special character. Every string matching `pattern`
+/// starts with them.
+pub fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let len = pattern.iter().position(|b| matches!(b, b'*' | b'?' | b'[' | b'\\')).unwrap_or(pattern.len());
+    &pattern[..len]
+}
+
+// `skip_longer` is set once a `*` failed to match with the rest of the string: a `*` further out trying a shorter
+// suffix cannot do any better. It keeps patterns like `a*a*a*a*b` from backtracking exponentially. `nesting` counts the
+// stars being backtracked over, one level of recursion each.
+fn matches_from(mut pattern: &[u8], mut string: &[u8], skip_longer: &mut bool, nesting: usize) -> bool {
+    if nesting > MAX_NESTING {
+        return false;
+    }
+    while let Some((&p, rest)) = pattern.split_first() {
+        match p {
+            b'*' => {
+                // Consecutive stars match the same as a single one
+                let rest = &rest[rest.iter().take_while(|&&b| b == b'*').count()..];
+                if rest.is_empty() {
+                    return true;
+                }
+                while !string.is_empty() {
+                    if matches_from(rest, string, skip_longer, nesting + 1) {
+                        return true;
+                    }
+                    if *skip_longer {
+                        return false;
+                    }
+                    string = &string[1..];
+                }
+                *skip_longer = true;
+                return false;
+            }
+            b'?' => {
+                let Some((_, s)) = string.split_first() else {
+                    return false;
+                };
+                string = s;
+                pattern = rest;
+            }
+            b'[' => {
+                let Some((&b, s)) = string.split_first() else {
+                    return false;
+                };
+                let (matched, rest) = match_class(rest, b);
+                if !matched {
+                    return false;
+                }
+                string = s;
+                pattern = rest;
+            }
+            _ => {
+                // A trailing backslash is matched literally
+                let (literal, rest) = match (p, rest) {
+                    (b'\\', [escaped, rest @ ..]) => (*escaped, rest),
+                    _ => (p, rest),
+                };
+                match string.split_first() {
+                    Some((&b, s)) if b == literal => string = s,
+                    _ => return false,
+                }
+                pattern = rest;
+            }
+        }
+    }
+    string.is_empty()
+}
+
+/// Match `b` against the class at the start of `pattern`, just after its `[`. Returns whether it matched and the rest
+/// of the pattern after the closing `]`.
+fn match_class(mut pattern: &[u8], b: u8) -> (bool, &[u8]) {
+    let negate = pattern.first() == Some(&b'^');
+    if negate {
+        pattern = &pattern[1..];
+    }
+    let mut matched = false;
+    loop {
+        match pattern {
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == b;
+                pattern = rest;
+            }
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            }
+            [] => break,
+            [start, b'-', end, rest @ ..] => {
+                let (low, high) = if start <= end { (start, end) } else { (end, start) };
+                matched |= (*low..=*high).contains(&b);
+                pattern = rest;
+            }
+            [c, rest @ ..] => {
+                matched |= *c == b;
+                pattern = rest;
+            }
+        }
+    }
+    (matched != negate, pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        super::matches(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn literal() {
+        assert!(matches("news", "news"));
+        assert!(!matches("news", "new"));
+        assert!(!matches("news", "newsy"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn question_mark() {
+        assert!(matches("h?llo", "hello"));
+        assert!(matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(!matches("?", ""));
+    }
+
+    #[test]
+    fn star() {
+        assert!(matches("news.*", "news."));
+        assert!(matches("news.*", "news.sport"));
+        assert!(!matches("news.*", "news"));
+        assert!(matches("*", ""));
+        assert!(matches("h*llo", "hllo"));
+        assert!(matches("h*llo", "heeeello"));
+        assert!(matches("**a**", "bab"));
+        assert!(matches("a*b*c", "aXXbYYc"));
+        assert!(!matches("a*b*c", "aXXbYY"));
+        assert!(matches("*.*.*", "a.b.c"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[a-z]llo", "hbllo"));
+        assert!(!matches("h[a-z]llo", "hBllo"));
+        assert!(matches("h[z-a]llo", "hbllo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(!matches("h[^a-z]llo", "hbllo"));
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("[-a]", "-"));
+        // An unterminated class ends with the pattern
+        assert!(matches("a[bc", "ac"));
+        assert!(!matches("[a]", ""));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("a\\?b", "a?b"));
+        assert!(!matches("a\\?b", "axb"));
+        assert!(matches("\\[a]", "[a]"));
+        // A trailing backslash matches itself
+        assert!(matches("a\\", "a\\"));
+    }
+
+    #[test]
+    fn deep_nesting_does_not_match() {
+        let pattern = "*a".repeat(100_000);
+        let string = "a".repeat(100_000);
+        assert!(!super::matches(pattern.as_bytes(), string.as_bytes()));
+        // Below the limit stars still backtrack
+        let pattern = "*a".repeat(MAX_NESTING);
+        assert!(super::matches(pattern.as_bytes(), string.as_bytes()));
+        assert!(!super::matches(format!("{}b", pattern).as_bytes(), string.as_bytes()));
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!(literal_prefix(b"news.*"), b"news.");
+        assert_eq!(literal_prefix(b"news"), b"news");
+        assert_eq!(literal_prefix(b"*"), b"");
+        assert_eq!(literal_prefix(b"a\\*"), b"a");
+    }
+}
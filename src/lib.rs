@@ -1,2 +1,35 @@
-mod connection_bytes;
-mod connection_vec_u8;
\ No newline at end of file
+//! A small Redis server and client built on Tokio.
+//!
+//! * `Server` accepts connections and serves them from a shared `Db`, `server::process` serves a single connection.
+//! * `Client` is a cloneable handle that multiplexes commands from many tasks over one connection.
+//! * `tls` builds the rustls configuration for both of them from PEM files.
+//! * `config::Config` reads the server settings from a `redis.conf` style file and the command line.
+//! * `Connection` reads and writes `Frame`s over any byte stream. `connection_vec_u8::Connection` does the same with
+//!   plain `Vec<u8>` buffers, `server::Builder::buffering` picks between the two, and `codec::RespCodec` plugs the
+//!   framing into `tokio_util::codec`.
+pub mod client;
+pub mod cmd;
+pub mod codec;
+pub mod config;
+pub mod connection;
+pub mod connection_bytes;
+pub mod connection_vec_u8;
+pub mod db;
+pub mod frame;
+pub mod glob;
+pub mod server;
+pub mod tls;
+
+pub use client::Client;
+pub use connection_bytes::Connection;
+pub use db::Db;
+pub use frame::Frame;
+pub use server::Server;
+
+/// Error returned by most functions.
+///
+/// Boxing keeps it simple: frame errors, I/O errors and command errors can all be returned with `?`.
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// A specialized `Result` type for this crate's operations.
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,1179 @@
+use std::fs::{self, Permissions};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio_stream::{Stream, StreamExt, StreamMap};
+use tracing::{debug, info, warn};
+use crate::cmd::Command;
+use crate::connection::{Buffering, FrameRead, FrameWrite, Timeouts};
+use crate::{connection_bytes, connection_vec_u8};
+use crate::db::{self, Db, PatternMessage};
+use crate::frame::{Frame, Limits, Protocol};
+use crate::tls::TlsAcceptor;
+use crate::Result;
+
+/// The messages of one subscribed channel
+type Messages = Pin<Box<dyn Stream<Item = Bytes> + Send>>;
+/// The messages of the channels matching one subscribed pattern
+type PatternMessages = Pin<Box<dyn Stream<Item = PatternMessage> + Send>>;
+
+/// How long the accept loop waits after its first failure to accept a connection, doubling up to `ACCEPT_BACKOFF_MAX`
+/// while it keeps failing
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// File descriptors kept for the listeners, the log and the like on top of one per client, Redis'
+/// `CONFIG_MIN_RESERVED_FDS`
+const RESERVED_FDS: usize = 32;
+
+/// How many messages a subscriber queues before it waits for them to be written, see `subscriber`
+const MAX_QUEUED_MESSAGES: usize = 64;
+
+/// A Redis server accepting TCP connections, optionally over TLS, and connections on a Unix domain socket, created
+/// with `Server::builder()`.
+///
+/// ```no_run
+/// # async fn run() -> my_redis::Result<()> {
+/// let server = my_redis::Server::builder().addr("127.0.0.1:6379").bind().await?;
+/// server.run().await
+/// # }
+/// ```
+pub struct Server {
+    listener: Option<TcpListener>,
+    unix: Option<UnixSocket>,
+    db: Db,
+    timeouts: Timeouts,
+    limits: Limits,
+    buffering: Buffering,
+    tls: Option<TlsAcceptor>,
+    shutdown_timeout: Duration,
+    clients: ClientCounts,
+}
+
+/// How many clients a `Server` has, and how many it turned away. Cloning it gives a handle that stays up to date
+/// while the server runs.
+#[derive(Clone)]
+pub struct ClientCounts {
+    // One permit per client the server may still admit, see `Builder::max_clients`
+    permits: Arc<Semaphore>,
+    max: usize,
+    rejected: Arc<AtomicU64>,
+}
+
+/// A listening Unix domain socket. The socket file is removed when it is dropped.
+struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+/// Configures and binds a `Server`
+pub struct Builder {
+    addr: Option<String>,
+    unix_socket: Option<PathBuf>,
+    unix_socket_perm: Option<u32>,
+    db: Option<Db>,
+    shards: usize,
+    timeouts: Timeouts,
+    limits: Limits,
+    buffering: Buffering,
+    tls: Option<TlsAcceptor>,
+    shutdown_timeout: Duration,
+    max_clients: usize,
+}
+
+impl Server {
+    /// A builder for a server on `127.0.0.1:6379` with its own empty `Db`
+    pub fn builder() -> Builder {
+        Builder {
+            addr: Some("127.0.0.1:6379".to_string()),
+            unix_socket: None,
+            unix_socket_perm: None,
+            db: None,
+            shards: db::DEFAULT_SHARDS,
+            // A client that starts sending a frame has this long to finish it, so slowloris-style clients cannot hold
+            // a task forever. Idle clients are kept, like Redis does with its default `timeout 0`.
+            timeouts: Timeouts {
+                idle: None,
+                stall: Some(Duration::from_secs(30)),
+            },
+            limits: Limits::default(),
+            buffering: Buffering::default(),
+            tls: None,
+            shutdown_timeout: Duration::from_secs(10),
+            // The Redis default
+            max_clients: 10000,
+        }
+    }
+
+    /// The TCP address the server is listening on, useful after binding to port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.listener {
+            Some(listener) => listener.local_addr(),
+            None => Err(io::Error::other("not listening on TCP")),
+        }
+    }
+
+    /// The store the server's connections read and write
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// The number of connected and rejected clients
+    pub fn clients(&self) -> ClientCounts {
+        self.clients.clone()
+    }
+
+    /// Accept connections forever, serving each one on its own task
+    pub async fn run(self) -> Result<()> {
+        self.run_until(std::future::pending::<()>()).await
+    }
+
+    /// Accept connections until `shutdown` completes, e.g. on `tokio::signal::ctrl_c()`, then shut down gracefully.
+    ///
+    /// The listeners are closed first so that no new client gets in. Every connection then finishes the command it is
+    /// running, writes the reply and closes. This waits for them for at most the builder's `shutdown_timeout`,
+    /// connections still open after that are left to the runtime, which drops them when it shuts down.
+    pub async fn run_until(self, shutdown: impl Future) -> Result<()> {
+        // Dropping the sender is the shutdown signal, every connection holds a receiver
+        let (notify_shutdown, _) = broadcast::channel(1);
+        // Every connection task holds a clone of the sender, so `recv` returns `None` once all of them have finished
+        let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel::<()>(1);
+
+        // The `select!` macro runs both branches concurrently and stops the other one as soon as one completes. The
+        // accept loop never does.
+        tokio::select! {
+            _ = self.accept_loop(&notify_shutdown, &shutdown_complete_tx) => {}
+            _ = shutdown => info!("shutting down"),
+        }
+
+        // Close the listeners, which removes the Unix socket file too
+        let Server { listener, unix, shutdown_timeout, .. } = self;
+        drop(listener);
+        drop(unix);
+
+        drop(notify_shutdown);
+        drop(shutdown_complete_tx);
+        if tokio::time::timeout(shutdown_timeout, shutdown_complete_rx.recv()).await.is_err() {
+            warn!("connections still open after {:?}, closing them", shutdown_timeout);
+        }
+        Ok(())
+    }
+
+    async fn accept_loop(&self, notify_shutdown: &broadcast::Sender<()>, shutdown_complete: &mpsc::Sender<()>) {
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+        // loop forever, accepting connections
+        loop {
+            let res = tokio::select! {
+                // The first item contains the socket and address of the new connection.
+                // The second item contains the IP and port of the new connection.
+                res = accept_tcp(&self.listener) => {
+                    res.map(|(socket, _)| self.spawn(socket, self.tls.clone(), notify_shutdown, shutdown_complete))
+                }
+                // Like Redis, TLS is only spoken over TCP. A Unix socket never leaves the host.
+                res = accept_unix(&self.unix) => {
+                    res.map(|(socket, _)| self.spawn(socket, None, notify_shutdown, shutdown_complete))
+                }
+            };
+            // Accepting fails when the process runs out of file descriptors, or when a client hangs up before it is
+            // accepted. Neither is a reason to stop serving everybody else. Out of descriptors, accepting again right
+            // away would fail again, so wait for connections to close first, longer each time.
+            match res {
+                Ok(()) => backoff = ACCEPT_BACKOFF_MIN,
+                Err(err) => {
+                    warn!("failed to accept a connection, retrying in {:?}: {}", backoff, err);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                }
+            }
+        }
+    }
+
+    /// Serve a client on a new task
+    fn spawn<T>(
+        &self,
+        socket: T,
+        tls: Option<TlsAcceptor>,
+        notify_shutdown: &broadcast::Sender<()>,
+        shutdown_complete: &mpsc::Sender<()>,
+    ) where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        // Clone the handle to the db
+        let db = self.db.clone();
+        let (limits, timeouts, buffering) = (self.limits, self.timeouts, self.buffering);
+        let mut notify_shutdown = notify_shutdown.subscribe();
+        // Held until the task ends, see `run_until`
+        let shutdown_complete = shutdown_complete.clone();
+        // Held until the task ends as well. Accepting the connection anyway and spending a short task on it lets the
+        // client know why it is being turned away, even over TLS.
+        let permit = self.clients.permits.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            self.clients.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        let admitted = permit.is_some();
+
+        // A new task is spawned for each inbound socket. The socket is
+        // moved to the new task and processed there. The TLS handshake happens there too, so a slow client
+        // cannot hold up the accept loop.
+        tokio::spawn(async move {
+            // The sender is never used to send anything, `recv` returns once it is dropped
+            let shutdown = async move {
+                let _ = notify_shutdown.recv().await;
+            };
+            let res = match tls {
+                Some(tls) => match handshake(&tls, socket, timeouts).await {
+                    Ok(stream) => serve(stream, db, limits, timeouts, buffering, admitted, shutdown).await,
+                    Err(err) => Err(err),
+                },
+                None => serve(socket, db, limits, timeouts, buffering, admitted, shutdown).await,
+            };
+            match res {
+                Ok(()) => {}
+                // Plenty of TLS clients hang up without sending `close_notify` first. Nothing is lost by it, every
+                // command read has been answered by then.
+                Err(err) if is_unexpected_eof(&*err) => debug!("connection closed without TLS close_notify"),
+                Err(err) => warn!("connection error: {}", err),
+            }
+            drop(permit);
+            drop(shutdown_complete);
+        });
+    }
+}
+
+impl ClientCounts {
+    fn new(max: usize) -> ClientCounts {
+        ClientCounts {
+            permits: Arc::new(Semaphore::new(max)),
+            max,
+            rejected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Clients connected right now
+    pub fn connected(&self) -> usize {
+        self.max - self.permits.available_permits()
+    }
+
+    /// Clients turned away so far because `max` were already connected
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// The most clients the server serves at once
+    pub fn max(&self) -> usize {
+        self.max
+    }
+}
+
+async fn accept_tcp(listener: &Option<TcpListener>) -> io::Result<(tokio::net::TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn accept_unix(unix: &Option<UnixSocket>) -> io::Result<(UnixStream, tokio::net::unix::SocketAddr)> {
+    match unix {
+        Some(unix) => unix.listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+impl UnixSocket {
+    /// Bind a Unix domain socket at `path`, replacing a stale socket file left behind by a server that did not shut
+    /// down cleanly
+    fn bind(path: &Path, perm: Option<u32>) -> Result<UnixSocket> {
+        match fs::symlink_metadata(path) {
+            Ok(meta) if !meta.file_type().is_socket() => {
+                return Err(format!("{} exists and is not a socket", path.display()).into());
+            }
+            Ok(_) => {
+                // Nobody listens on a stale socket. If somebody does, the socket is still in use and must be left alone.
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(format!("{} is in use by another server", path.display()).into());
+                }
+                fs::remove_file(path)?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let listener = UnixListener::bind(path)?;
+        // From here on the socket file is removed when `UnixSocket` is dropped, including on errors below
+        let unix = UnixSocket { listener, path: path.to_path_buf() };
+        if let Some(perm) = perm {
+            fs::set_permissions(path, Permissions::from_mode(perm))?;
+        }
+        Ok(unix)
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Builder {
+    /// The address to listen on, e.g. `127.0.0.1:6379` or `0.0.0.0:0`
+    pub fn addr(mut self, addr: impl Into<String>) -> Builder {
+        self.addr = Some(addr.into());
+        self
+    }
+
+    /// Do not listen on TCP at all, only on the Unix domain socket
+    pub fn without_tcp(mut self) -> Builder {
+        self.addr = None;
+        self
+    }
+
+    /// Also listen on a Unix domain socket at `path`, like Redis' `unixsocket`.
+    ///
+    /// A socket file left at `path` by a server that did not shut down cleanly is replaced. The file is removed again
+    /// when the `Server` is dropped.
+    pub fn unix_socket(mut self, path: impl Into<PathBuf>) -> Builder {
+        self.unix_socket = Some(path.into());
+        self
+    }
+
+    /// Permissions of the Unix domain socket file, e.g. `0o700`, like Redis' `unixsocketperm`. By default they are
+    /// left to the process umask.
+    pub fn unix_socket_perm(mut self, mode: u32) -> Builder {
+        self.unix_socket_perm = Some(mode);
+        self
+    }
+
+    /// Serve an existing `Db`, e.g. one shared with another server
+    pub fn db(mut self, db: Db) -> Builder {
+        self.db = Some(db);
+        self
+    }
+
+    /// The number of shards of the server's own `Db`, see `Db::with_shards`. Ignored if a `Db` is passed to
+    /// `Builder::db`, otherwise `bind` fails if it is 0 or more than `db::MAX_SHARDS`.
+    pub fn shards(mut self, shards: usize) -> Builder {
+        self.shards = shards;
+        self
+    }
+
+    /// How long connections wait on a quiet client
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Builder {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Caps on the frames clients may send
+    pub fn limits(mut self, limits: Limits) -> Builder {
+        self.limits = limits;
+        self
+    }
+
+    /// How connections buffer what they read and write, `BytesMut` unless set otherwise
+    pub fn buffering(mut self, buffering: Buffering) -> Builder {
+        self.buffering = buffering;
+        self
+    }
+
+    /// Only accept TLS connections, see `tls::acceptor`
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Builder {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// How long `Server::run_until` waits for open connections to finish when shutting down
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Builder {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// The most clients served at once, like Redis' `maxclients`. Clients over the limit get an error reply and are
+    /// disconnected.
+    ///
+    /// Like Redis, `bind` raises the process' open files limit to make room for that many clients, and lowers the
+    /// maximum with a warning if it cannot. `bind` fails if it is 0 or more than `Semaphore::MAX_PERMITS`.
+    pub fn max_clients(mut self, max: usize) -> Builder {
+        self.max_clients = max;
+        self
+    }
+
+    /// Bind the listeners. The server does not accept connections until `Server::run` is called.
+    pub async fn bind(self) -> Result<Server> {
+        if self.addr.is_none() && self.unix_socket.is_none() {
+            return Err("the server needs a TCP address or a Unix socket to listen on".into());
+        }
+        if self.db.is_none() && (self.shards == 0 || self.shards > db::MAX_SHARDS) {
+            return Err(format!("shards must be between 1 and {}", db::MAX_SHARDS).into());
+        }
+        if self.max_clients == 0 || self.max_clients > Semaphore::MAX_PERMITS {
+            return Err(format!("maxclients must be between 1 and {}", Semaphore::MAX_PERMITS).into());
+        }
+        let unix = match &self.unix_socket {
+            Some(path) => Some(UnixSocket::bind(path, self.unix_socket_perm)?),
+            None => None,
+        };
+        let listener = match &self.addr {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        let max_clients = fit_open_files_limit(self.max_clients)?;
+        Ok(Server {
+            listener,
+            unix,
+            db: self.db.unwrap_or_else(|| Db::with_shards(self.shards)),
+            timeouts: self.timeouts,
+            limits: self.limits,
+            buffering: self.buffering,
+            tls: self.tls,
+            shutdown_timeout: self.shutdown_timeout,
+            clients: ClientCounts::new(max_clients),
+        })
+    }
+}
+
+/// Make sure the process may open a descriptor for each of `max_clients` clients plus `RESERVED_FDS`, raising the soft
+/// open files limit up to the hard one if needed. Returns how many clients fit, which is less than `max_clients` if the
+/// limit could not be raised far enough.
+fn fit_open_files_limit(max_clients: usize) -> Result<usize> {
+    let wanted = max_clients.saturating_add(RESERVED_FDS) as libc::rlim_t;
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: `limit` is a valid `rlimit` for `getrlimit` to fill in
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(format!("failed to read the open files limit: {}", io::Error::last_os_error()).into());
+    }
+    if limit.rlim_cur == libc::RLIM_INFINITY || limit.rlim_cur >= wanted {
+        return Ok(max_clients);
+    }
+
+    let raised = libc::rlimit {
+        rlim_cur: wanted.min(limit.rlim_max),
+        rlim_max: limit.rlim_max,
+    };
+    // SAFETY: `raised` is a valid `rlimit`
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+        limit.rlim_cur = raised.rlim_cur;
+    }
+    if limit.rlim_cur >= wanted {
+        info!("raised the open files limit to {}", limit.rlim_cur);
+        return Ok(max_clients);
+    }
+
+    let fit = usize::try_from(limit.rlim_cur).unwrap_or(usize::MAX).saturating_sub(RESERVED_FDS);
+    if fit == 0 {
+        return Err(format!("the open files limit of {} is too low to serve any client", limit.rlim_cur).into());
+    }
+    warn!(
+        "the open files limit of {} leaves room for {} clients only, lowering maxclients from {}",
+        limit.rlim_cur, fit, max_clients
+    );
+    Ok(fit)
+}
+
+/// Whether `err` is a stream ending where it should not, which for TLS means the peer closed the connection without
+/// `close_notify`
+pub(crate) fn is_unexpected_eof(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<io::Error>().is_some_and(|err| err.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+/// Run the server side of the TLS handshake. It is bounded by the stall timeout, like a frame that stops arriving half
+/// way through.
+async fn handshake<T: AsyncRead + AsyncWrite + Unpin>(
+    tls: &TlsAcceptor,
+    stream: T,
+    timeouts: Timeouts,
+) -> Result<tokio_rustls::server::TlsStream<T>> {
+    let Some(stall) = timeouts.stall else {
+        return Ok(tls.accept(stream).await?);
+    };
+    match tokio::time::timeout(stall, tls.accept(stream)).await {
+        Ok(stream) => Ok(stream?),
+        Err(_) => Err("timed out waiting for the TLS handshake".into()),
+    }
+}
+
+/// Serve a client over `stream` with the server's settings, or turn it away if it was not `admitted`
+async fn serve<T: AsyncRead + AsyncWrite + Unpin + Send>(
+    stream: T,
+    db: Db,
+    limits: Limits,
+    timeouts: Timeouts,
+    buffering: Buffering,
+    admitted: bool,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    match buffering {
+        Buffering::Bytes => {
+            let connection = connection_bytes::Connection::with_limits(stream, limits);
+            serve_connection(connection, db, timeouts, admitted, shutdown).await
+        }
+        Buffering::Vec => {
+            let connection = connection_vec_u8::Connection::with_limits(stream, limits);
+            serve_connection(connection, db, timeouts, admitted, shutdown).await
+        }
+    }
+}
+
+/// The part of `serve` that is the same whichever `Connection` it picked
+async fn serve_connection<C: FrameRead + FrameWrite>(
+    mut connection: C,
+    db: Db,
+    timeouts: Timeouts,
+    admitted: bool,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    connection.set_timeouts(timeouts);
+    if !admitted {
+        // Same reply as Redis. The connection is closed when it is dropped.
+        return connection.write_frame(&Frame::Error("ERR max number of clients reached".to_string())).await;
+    }
+    process(connection, db, shutdown).await
+}
+
+/// Serve a single client until it disconnects or `shutdown` completes.
+///
+/// The `Connection` lets us read/write redis **frames** instead of byte streams. Both `Connection` types in this crate
+/// implement `FrameRead` and `FrameWrite`, so either buffering strategy can be plugged in.
+///
+/// `shutdown` is only checked while waiting for the next command, a command that has been read is always run and
+/// answered first. Pass `std::future::pending()` to serve the client until it goes away.
+///
+/// Unknown and malformed commands are answered with an error reply. An error is only returned when the connection has
+/// to be closed: an I/O error, or a frame that could not be parsed at all, which `read_frame` has already answered.
+///
+/// `SUBSCRIBE` and `PSUBSCRIBE` put the connection in subscriber mode until it has left every channel and pattern, see
+/// `subscriber`.
+pub async fn process<C: FrameRead + FrameWrite>(mut connection: C, db: Db, shutdown: impl Future<Output = ()>) -> Result<()> {
+    tokio::pin!(shutdown);
+    loop {
+        // Use `read_frame` to receive a command from the connection.
+        let frame = tokio::select! {
+            res = connection.read_frame() => match res? {
+                Some(frame) => frame,
+                None => break,
+            },
+            _ = &mut shutdown => break,
+        };
+        // `HELLO` switches the protocol version of this connection, it never reaches the command dispatch
+        if let Some(response) = connection.negotiate(&frame) {
+            connection.queue_frame(&response);
+            continue;
+        }
+        let response = match Command::from_frame(frame) {
+            // Without any subscriptions an `UNSUBSCRIBE` only gets its replies
+            Ok(
+                command @ (Command::Subscribe { .. }
+                | Command::Unsubscribe { .. }
+                | Command::Psubscribe { .. }
+                | Command::Punsubscribe { .. }),
+            ) => {
+                let mut subscriptions = Subscriptions::default();
+                subscriptions.update(&mut connection, &db, &command);
+                if subscriptions.is_empty() || subscriber(&mut connection, &db, subscriptions, shutdown.as_mut()).await? {
+                    continue;
+                }
+                break;
+            }
+            Ok(command) => command.apply(&db),
+            // A malformed command only fails itself, the client can carry on with the next one
+            Err(err) => Frame::Error(format!("ERR {}", err)),
+        };
+        // Queue the response. `read_frame` flushes it once the commands buffered so far have all been answered, so a
+        // pipelined batch is answered in one write.
+        connection.queue_frame(&response);
+    }
+    // Replies to the last commands read may still be queued, e.g. when shutting down
+    connection.flush().await?;
+    debug!("connection closed, {:?}", connection.stats());
+    Ok(())
+}
+
+/// Serve a client in subscriber mode, after it subscribed to `subscriptions`.
+///
+/// Messages published to the subscribed channels, and to the channels matching the subscribed patterns, are pushed to
+/// the client as they arrive. Meanwhile the client may only send subscription commands and `PING`. The idle timeout is
+/// lifted, as a subscriber has no reason to talk.
+///
+/// Returns `true` once the client has left every channel and pattern and is back to sending regular commands, `false`
+/// if the connection is done: the client went away or `shutdown` completed.
+async fn subscriber<C: FrameRead + FrameWrite>(
+    connection: &mut C,
+    db: &Db,
+    mut subscriptions: Subscriptions,
+    shutdown: Pin<&mut impl Future<Output = ()>>,
+) -> Result<bool> {
+    let timeouts = connection.timeouts();
+    connection.set_timeouts(Timeouts { idle: None, ..timeouts });
+    let res = serve_subscriber(connection, db, &mut subscriptions, shutdown).await;
+    connection.set_timeouts(timeouts);
+    // However the connection left subscriber mode, an I/O error included, its channels and patterns are released.
+    // Otherwise they would stay registered in the `Db` until the next publish to them.
+    subscriptions.release(db);
+    res
+}
+
+/// The loop of `subscriber`, which leaves the subscriptions for it to release
+async fn serve_subscriber<C: FrameRead + FrameWrite>(
+    connection: &mut C,
+    db: &Db,
+    subscriptions: &mut Subscriptions,
+    mut shutdown: Pin<&mut impl Future<Output = ()>>,
+) -> Result<bool> {
+    // Messages are queued like replies and go out when `read_frame` waits for the client. While they keep coming, a
+    // flush every `MAX_QUEUED_MESSAGES` makes sure they still do, and lets a slow client hold the messages back.
+    let mut queued = 0;
+    let subscribed = loop {
+        if subscriptions.is_empty() {
+            break true;
+        }
+        if queued >= MAX_QUEUED_MESSAGES {
+            connection.flush().await?;
+            queued = 0;
+        }
+        tokio::select! {
+            Some((channel, message)) = subscriptions.channels.next() => {
+                let frame = Frame::Push(vec![Frame::Bulk("message".into()), Frame::Bulk(channel.into()), Frame::Bulk(message)]);
+                connection.queue_frame(&frame);
+                queued += 1;
+            }
+            Some((pattern, (channel, message))) = subscriptions.patterns.next() => {
+                let frame = Frame::Push(vec![
+                    Frame::Bulk("pmessage".into()),
+                    Frame::Bulk(pattern.into()),
+                    Frame::Bulk(channel),
+                    Frame::Bulk(message),
+                ]);
+                connection.queue_frame(&frame);
+                queued += 1;
+            }
+            res = connection.read_frame() => {
+                let Some(frame) = res? else {
+                    break false;
+                };
+                let name = match &frame {
+                    Frame::Array(args) if !args.is_empty() => args[0].to_string(),
+                    _ => String::new(),
+                };
+                match Command::from_frame(frame) {
+                    Ok(command) if subscriptions.update(connection, db, &command) => {}
+                    // RESP2 has no out-of-band frames, so a subscriber gets its pong the same shape as a message
+                    Ok(Command::Ping { message }) if connection.protocol() == Protocol::Resp2 => {
+                        let message = message.unwrap_or_default();
+                        connection.queue_frame(&Frame::Array(vec![Frame::Bulk("pong".into()), Frame::Bulk(message)]));
+                    }
+                    Ok(command @ Command::Ping { .. }) => connection.queue_frame(&command.apply(db)),
+                    Ok(_) => connection.queue_frame(&Frame::Error(format!(
+                        "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context",
+                        name.to_lowercase()
+                    ))),
+                    Err(err) => connection.queue_frame(&Frame::Error(format!("ERR {}", err))),
+                }
+            }
+            _ = &mut shutdown => break false,
+        }
+    };
+    connection.flush().await?;
+    Ok(subscribed)
+}
+
+/// The channels and patterns a connection in subscriber mode listens to
+#[derive(Default)]
+struct Subscriptions {
+    channels: StreamMap<String, Messages>,
+    patterns: StreamMap<String, PatternMessages>,
+}
+
+impl Subscriptions {
+    fn len(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Run a subscription command, queueing a reply for each channel or pattern it names. Returns `false` for any other
+    /// command.
+    fn update<C: FrameWrite>(&mut self, connection: &mut C, db: &Db, command: &Command) -> bool {
+        match command {
+            Command::Subscribe { channels } => {
+                for channel in channels {
+                    if !self.channels.contains_key(channel) {
+                        self.channels.insert(channel.clone(), Box::pin(messages(db.subscribe(channel.clone()))));
+                    }
+                    connection.queue_frame(&self.reply("subscribe", Some(channel)));
+                }
+            }
+            Command::Psubscribe { patterns } => {
+                for pattern in patterns {
+                    if !self.patterns.contains_key(pattern) {
+                        self.patterns.insert(pattern.clone(), Box::pin(messages(db.psubscribe(pattern.clone()))));
+                    }
+                    connection.queue_frame(&self.reply("psubscribe", Some(pattern)));
+                }
+            }
+            Command::Unsubscribe { channels } => {
+                let channels = match channels.is_empty() {
+                    true => self.channels.keys().cloned().collect(),
+                    false => channels.clone(),
+                };
+                // Redis still confirms an `UNSUBSCRIBE` that had nothing to leave
+                if channels.is_empty() {
+                    connection.queue_frame(&self.reply("unsubscribe", None));
+                }
+                for channel in channels {
+                    if self.channels.remove(&channel).is_some() {
+                        db.release_channel(&channel);
+                    }
+                    connection.queue_frame(&self.reply("unsubscribe", Some(&channel)));
+                }
+            }
+            Command::Punsubscribe { patterns } => {
+                let patterns = match patterns.is_empty() {
+                    true => self.patterns.keys().cloned().collect(),
+                    false => patterns.clone(),
+                };
+                if patterns.is_empty() {
+                    connection.queue_frame(&self.reply("punsubscribe", None));
+                }
+                for pattern in patterns {
+                    if self.patterns.remove(&pattern).is_some() {
+                        db.release_pattern(&pattern);
+                    }
+                    connection.queue_frame(&self.reply("punsubscribe", Some(&pattern)));
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The `[kind, name, count]` reply to a subscription command, `count` being the channels and patterns still
+    /// subscribed to
+    fn reply(&self, kind: &'static str, name: Option<&str>) -> Frame {
+        let name = match name {
+            Some(name) => Frame::Bulk(Bytes::copy_from_slice(name.as_bytes())),
+            None => Frame::Null,
+        };
+        Frame::Push(vec![Frame::Bulk(kind.into()), name, Frame::Integer(self.len() as i64)])
+    }
+
+    /// Leave every channel and pattern
+    fn release(self, db: &Db) {
+        let channels: Vec<String> = self.channels.keys().cloned().collect();
+        let patterns: Vec<String> = self.patterns.keys().cloned().collect();
+        // The receivers have to be gone before the channels and patterns count as unused
+        drop(self);
+        for channel in channels {
+            db.release_channel(&channel);
+        }
+        for pattern in patterns {
+            db.release_pattern(&pattern);
+        }
+    }
+}
+
+/// The messages received on `rx`. A subscriber that lagged behind skips the messages it missed rather than failing.
+fn messages<T: Clone + Send + 'static>(rx: broadcast::Receiver<T>) -> impl Stream<Item = T> + Send {
+    futures::stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(message) => return Some((message, rx)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::task::JoinHandle;
+
+    const BUFFERINGS: [Buffering; 2] = [Buffering::Bytes, Buffering::Vec];
+
+    /// Serve a client over an in-memory pipe, returning the client's end of it and the task serving it
+    fn connect(buffering: Buffering) -> (DuplexStream, JoinHandle<Result<()>>) {
+        connect_to(Db::new(), buffering)
+    }
+
+    /// `connect`, serving `db`
+    fn connect_to(db: Db, buffering: Buffering) -> (DuplexStream, JoinHandle<Result<()>>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (limits, timeouts) = (Limits::default(), Timeouts::default());
+        let task = tokio::spawn(serve(server, db, limits, timeouts, buffering, true, std::future::pending()));
+        (client, task)
+    }
+
+    /// A stream that counts the calls to `poll_write`, i.e. the writes a connection makes
+    struct CountWrites<T> {
+        inner: T,
+        writes: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl<T: AsyncRead + Unpin> AsyncRead for CountWrites<T> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl<T: AsyncWrite + Unpin> AsyncWrite for CountWrites<T> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Send `request` and check the server answers with exactly `expected`
+    async fn roundtrip(client: &mut (impl AsyncRead + AsyncWrite + Unpin), request: &[u8], expected: &[u8]) {
+        client.write_all(request).await.unwrap();
+        let mut reply = vec![0; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply.escape_ascii().to_string(), expected.escape_ascii().to_string());
+    }
+
+    /// The reply to `HELLO`, as a RESP3 map or flattened into a RESP2 array
+    fn hello(protocol: Protocol) -> Vec<u8> {
+        let (header, proto) = match protocol {
+            Protocol::Resp2 => ("*12", 2),
+            Protocol::Resp3 => ("%6", 3),
+        };
+        let version = env!("CARGO_PKG_VERSION");
+        format!(
+            "{}\r\n$6\r\nserver\r\n$8\r\nmy-redis\r\n$7\r\nversion\r\n${}\r\n{}\r\n$5\r\nproto\r\n:{}\r\n\
+             $4\r\nmode\r\n$10\r\nstandalone\r\n$4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+            header,
+            version.len(),
+            version,
+            proto
+        )
+        .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn get_and_set() {
+        for buffering in BUFFERINGS {
+            let (mut client, task) = connect(buffering);
+            roundtrip(&mut client, b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n", b"$-1\r\n").await;
+            roundtrip(&mut client, b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n", b"+OK\r\n").await;
+            roundtrip(&mut client, b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n", b"$3\r\nbar\r\n").await;
+            // Inline commands get the same replies
+            roundtrip(&mut client, b"GET foo\r\n", b"$3\r\nbar\r\n").await;
+
+            // A client hanging up between commands is not an error
+            drop(client);
+            task.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn pipelined_commands() {
+        for buffering in BUFFERINGS {
+            let (mut client, _task) = connect(buffering);
+            roundtrip(
+                &mut client,
+                b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*2\r\n$4\r\nINCR\r\n$1\r\na\r\n\
+                  PING\r\n*2\r\n$3\r\nGET\r\n$1\r\na\r\n",
+                b"+OK\r\n:2\r\n+PONG\r\n$1\r\n2\r\n",
+            )
+            .await;
+            // Command errors are answered and the connection stays open
+            roundtrip(
+                &mut client,
+                b"NOSUCH a b\r\nGET\r\nPING\r\n",
+                b"-ERR unknown command 'NOSUCH', with args beginning with: 'a' 'b' \r\n\
+                  -ERR wrong number of arguments for 'get' command\r\n+PONG\r\n",
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn pipelined_replies_written_at_once() {
+        for buffering in BUFFERINGS {
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let writes = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+            let server = CountWrites { inner: server, writes: writes.clone() };
+            let (limits, timeouts) = (Limits::default(), Timeouts::default());
+            tokio::spawn(serve(server, Db::new(), limits, timeouts, buffering, true, std::future::pending()));
+
+            let mut expected = b"+PONG\r\n+OK\r\n".to_vec();
+            expected.extend_from_slice(&hello(Protocol::Resp2));
+            expected.extend_from_slice(b":2\r\n$1\r\n2\r\n");
+            roundtrip(&mut client, b"PING\r\nSET a 1\r\nHELLO\r\nINCR a\r\nGET a\r\n", &expected).await;
+            assert_eq!(writes.load(Ordering::Relaxed), 1, "{:?}", buffering);
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_larger_than_the_read_buffer() {
+        for buffering in BUFFERINGS {
+            let (mut client, _task) = connect(buffering);
+            let (mut request, mut expected) = (vec![], vec![]);
+            for i in 0..2000 {
+                request.extend_from_slice(format!("INCRBY counter {}\r\n", i).as_bytes());
+                expected.extend_from_slice(format!(":{}\r\n", i * (i + 1) / 2).as_bytes());
+            }
+            roundtrip(&mut client, &request, &expected).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn protocol_error_closes_the_connection() {
+        for buffering in BUFFERINGS {
+            let (mut client, task) = connect(buffering);
+            roundtrip(
+                &mut client,
+                b"PING\r\n*1\r\n$x\r\n",
+                b"+PONG\r\n-ERR Protocol error: unexpected byte 'x' at offset 5\r\n",
+            )
+            .await;
+            let mut rest = vec![];
+            client.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            assert!(task.await.unwrap().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn resp3_frames_downgraded_on_resp2() {
+        for buffering in BUFFERINGS {
+            let (mut client, _task) = connect(buffering);
+            roundtrip(&mut client, b"HELLO\r\n", &hello(Protocol::Resp2)).await;
+            roundtrip(&mut client, b"GET nothing\r\n", b"$-1\r\n").await;
+            roundtrip(&mut client, b"HELLO 3\r\n", &hello(Protocol::Resp3)).await;
+            roundtrip(&mut client, b"GET nothing\r\n", b"_\r\n").await;
+            roundtrip(&mut client, b"SUBSCRIBE ch\r\n", b">3\r\n$9\r\nsubscribe\r\n$2\r\nch\r\n:1\r\n").await;
+            roundtrip(&mut client, b"UNSUBSCRIBE\r\n", b">3\r\n$11\r\nunsubscribe\r\n$2\r\nch\r\n:0\r\n").await;
+            roundtrip(&mut client, b"HELLO 2\r\n", &hello(Protocol::Resp2)).await;
+            roundtrip(&mut client, b"SUBSCRIBE ch\r\n", b"*3\r\n$9\r\nsubscribe\r\n$2\r\nch\r\n:1\r\n").await;
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_reaches_subscribers() {
+        for buffering in BUFFERINGS {
+            let db = Db::new();
+            let (mut subscriber, _task) = connect_to(db.clone(), buffering);
+            let (mut publisher, _task) = connect_to(db.clone(), buffering);
+            roundtrip(&mut publisher, b"PUBLISH news hello\r\n", b":0\r\n").await;
+            roundtrip(&mut subscriber, b"SUBSCRIBE news\r\n", b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n").await;
+            roundtrip(
+                &mut subscriber,
+                b"PSUBSCRIBE n*\r\n",
+                b"*3\r\n$10\r\npsubscribe\r\n$2\r\nn*\r\n:2\r\n",
+            )
+            .await;
+
+            // The reply counts every receiver, the channel's and the pattern's. The two messages may arrive in either
+            // order.
+            roundtrip(&mut publisher, b"PUBLISH news hello\r\n", b":2\r\n").await;
+            let message: &[u8] = b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n";
+            let pmessage: &[u8] = b"*4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnews\r\n$5\r\nhello\r\n";
+            let mut received = vec![0; message.len() + pmessage.len()];
+            subscriber.read_exact(&mut received).await.unwrap();
+            assert!(received == [message, pmessage].concat() || received == [pmessage, message].concat());
+            roundtrip(&mut publisher, b"PUBLISH nope hi\r\n", b":1\r\n").await;
+            roundtrip(&mut subscriber, b"", b"*4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnope\r\n$2\r\nhi\r\n").await;
+        }
+    }
+
+    #[tokio::test]
+    async fn subscriptions_released_on_connection_errors() {
+        for buffering in BUFFERINGS {
+            let db = Db::new();
+            let (mut client, task) = connect_to(db.clone(), buffering);
+            roundtrip(&mut client, b"SUBSCRIBE a b\r\n", b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n").await;
+            roundtrip(&mut client, b"", b"*3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n").await;
+            roundtrip(&mut client, b"PSUBSCRIBE p*\r\n", b"*3\r\n$10\r\npsubscribe\r\n$2\r\np*\r\n:3\r\n").await;
+            assert_eq!(db.registered(), (2, 1));
+
+            // The client hangs up half way through a frame, which is an error
+            client.write_all(b"*2\r\n$4\r\nPING").await.unwrap();
+            drop(client);
+            assert!(task.await.unwrap().is_err());
+            assert_eq!(db.registered(), (0, 0));
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_rejects_shard_counts_out_of_range() {
+        for shards in [0, db::MAX_SHARDS + 1] {
+            let res = Server::builder().addr("127.0.0.1:0").shards(shards).bind().await;
+            assert_eq!(res.err().unwrap().to_string(), format!("shards must be between 1 and {}", db::MAX_SHARDS));
+        }
+        let server = Server::builder().addr("127.0.0.1:0").shards(db::MAX_SHARDS).bind().await.unwrap();
+        assert_eq!(server.db().shards(), db::MAX_SHARDS);
+        // The count is not used with a `Db` of its own
+        let server = Server::builder().addr("127.0.0.1:0").db(Db::with_shards(3)).shards(0).bind().await.unwrap();
+        assert_eq!(server.db().shards(), 3);
+    }
+
+    #[tokio::test]
+    async fn clients_over_the_limit_turned_away() {
+        for max in [0, Semaphore::MAX_PERMITS + 1] {
+            let res = Server::builder().addr("127.0.0.1:0").max_clients(max).bind().await;
+            let expected = format!("maxclients must be between 1 and {}", Semaphore::MAX_PERMITS);
+            assert_eq!(res.err().unwrap().to_string(), expected);
+        }
+
+        let server = Server::builder().addr("127.0.0.1:0").max_clients(2).bind().await.unwrap();
+        let (addr, clients) = (server.local_addr().unwrap(), server.clients());
+        tokio::spawn(server.run());
+        let connect = || async move {
+            let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+            socket.write_all(b"PING\r\n").await.unwrap();
+            let mut reply = vec![0; 7];
+            socket.read_exact(&mut reply).await.unwrap();
+            (socket, reply)
+        };
+
+        let (first, reply) = connect().await;
+        assert_eq!(reply, b"+PONG\r\n");
+        let (_second, reply) = connect().await;
+        assert_eq!(reply, b"+PONG\r\n");
+        assert_eq!((clients.connected(), clients.rejected()), (2, 0));
+
+        // Sending a command first would risk a reset instead of the reply, as the server closes without reading it
+        let mut third = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut reply = vec![];
+        third.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"-ERR max number of clients reached\r\n");
+        assert_eq!((clients.connected(), clients.rejected()), (2, 1));
+
+        // A client leaving makes room for the next one
+        drop(first);
+        while clients.connected() > 1 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let (_fourth, reply) = connect().await;
+        assert_eq!(reply, b"+PONG\r\n");
+        assert_eq!((clients.connected(), clients.rejected(), clients.max()), (2, 1, 2));
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        // Waiting out the timeout would also make `run_until` return, so make it long enough to tell the two apart
+        let builder = Server::builder().addr("127.0.0.1:0").shutdown_timeout(Duration::from_secs(60));
+        let server = builder.bind().await.unwrap();
+        let (addr, db) = (server.local_addr().unwrap(), server.db().clone());
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let task = tokio::spawn(server.run_until(rx));
+
+        let mut idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        roundtrip(&mut idle, b"PING\r\n", b"+PONG\r\n").await;
+        // A command the server has read, followed by half of another one
+        let mut busy = tokio::net::TcpStream::connect(addr).await.unwrap();
+        busy.write_all(b"SET k v\r\n*3\r\n$3\r\nSET").await.unwrap();
+        while db.get("k").is_none() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        tx.send(()).unwrap();
+        // The command read is answered, the half-sent one is dropped, and both connections are closed
+        let mut reply = vec![];
+        busy.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"+OK\r\n");
+        let mut reply = vec![];
+        idle.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"");
+        tokio::time::timeout(Duration::from_secs(10), task).await.unwrap().unwrap().unwrap();
+        // No longer listening
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    /// A directory of its own for a test's socket files, removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("my-redis-unix-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn unix_socket_file() {
+        let dir = TempDir::new("file");
+        let path = dir.0.join("redis.sock");
+
+        let unix = UnixSocket::bind(&path, Some(0o600)).unwrap();
+        let meta = fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        // Somebody is listening, so the socket is left alone
+        let err = UnixSocket::bind(&path, None).err().unwrap();
+        assert_eq!(err.to_string(), format!("{} is in use by another server", path.display()));
+        assert!(path.exists());
+        drop(unix);
+        assert!(!path.exists());
+
+        // A socket file nobody listens on is left behind by a server that did not shut down cleanly
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let unix = UnixSocket::bind(&path, Some(0o770)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o770);
+        drop(unix);
+
+        // Anything else is not ours to replace
+        fs::write(&path, "data").unwrap();
+        let err = UnixSocket::bind(&path, None).err().unwrap();
+        assert_eq!(err.to_string(), format!("{} exists and is not a socket", path.display()));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+    }
+
+    #[tokio::test]
+    async fn serve_on_unix_socket() {
+        let dir = TempDir::new("serve");
+        let path = dir.0.join("redis.sock");
+        let server = Server::builder().without_tcp().unix_socket(&path).bind().await.unwrap();
+        assert!(server.local_addr().is_err());
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let task = tokio::spawn(server.run_until(rx));
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        client.write_all(b"SET k v\r\nGET k\r\n").await.unwrap();
+        let mut reply = vec![0; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, b"+OK\r\n$1\r\nv\r\n");
+        drop(client);
+
+        // Shutting down removes the socket file
+        tx.send(()).unwrap();
+        task.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,202 @@
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use crate::Result;
+
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Build the server side of TLS from PEM files, the same files Redis takes as `tls-cert-file` and `tls-key-file`.
+///
+/// With `client_ca`, clients must present a certificate signed by one of the CAs in that file (mutual TLS, Redis'
+/// `tls-auth-clients yes`). Without it, clients are not asked for a certificate.
+pub fn acceptor(cert: impl AsRef<Path>, key: impl AsRef<Path>, client_ca: Option<&Path>) -> Result<TlsAcceptor> {
+    let builder = ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots(client_ca)?), provider()).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs(cert)?, private_key(key)?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Build the client side of TLS, trusting the CAs in the PEM file `ca`.
+///
+/// `identity` is a certificate and key file pair, presented to servers that require mutual TLS.
+pub fn connector(ca: impl AsRef<Path>, identity: Option<(&Path, &Path)>) -> Result<TlsConnector> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(certs(cert)?, private_key(key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Parse the name a server certificate must be valid for, a DNS name or an IP address
+pub fn server_name(name: &str) -> Result<ServerName<'static>> {
+    Ok(ServerName::try_from(name.to_string())?)
+}
+
+// Pick the crypto provider explicitly rather than relying on the process wide default, which is ambiguous as soon as
+// another crate in the build enables a second one
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Read every certificate in a PEM file
+fn certs(path: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>> {
+    let path = path.as_ref();
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", path.display()).into());
+    }
+    Ok(certs)
+}
+
+/// Read the first private key in a PEM file
+fn private_key(path: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>> {
+    let path = path.as_ref();
+    Ok(PrivateKeyDer::from_pem_file(path).map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+/// Read the CA certificates to trust from a PEM file
+fn roots(path: impl AsRef<Path>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use bytes::Bytes;
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use tokio::io::DuplexStream;
+    use crate::connection_bytes::Connection;
+    use crate::db::Db;
+    use crate::frame::Frame;
+    use crate::server;
+
+    /// PEM files for a CA and a server and a client certificate signed by it, in a directory removed on drop
+    struct Pki {
+        dir: PathBuf,
+    }
+
+    impl Pki {
+        fn generate(name: &str) -> Pki {
+            let dir = std::env::temp_dir().join(format!("my-redis-tls-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            let ca_key = KeyPair::generate().unwrap();
+            let mut ca = CertificateParams::new(vec![]).unwrap();
+            ca.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = ca.self_signed(&ca_key).unwrap();
+            std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+            for (name, usage) in [
+                ("server", ExtendedKeyUsagePurpose::ServerAuth),
+                ("client", ExtendedKeyUsagePurpose::ClientAuth),
+            ] {
+                let key = KeyPair::generate().unwrap();
+                let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+                params.extended_key_usages = vec![usage];
+                let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+                std::fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+                std::fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+            }
+            Pki { dir }
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.dir.join(file)
+        }
+
+        fn acceptor(&self, mutual: bool) -> TlsAcceptor {
+            let client_ca = self.path("ca.pem");
+            acceptor(self.path("server.pem"), self.path("server.key"), mutual.then_some(client_ca.as_path())).unwrap()
+        }
+
+        fn connector(&self, identity: bool) -> TlsConnector {
+            let (cert, key) = (self.path("client.pem"), self.path("client.key"));
+            connector(self.path("ca.pem"), identity.then_some((cert.as_path(), key.as_path()))).unwrap()
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    type ServerStream = tokio_rustls::server::TlsStream<DuplexStream>;
+    type ClientStream = tokio_rustls::client::TlsStream<DuplexStream>;
+
+    /// Run both sides of a handshake over an in-memory pipe
+    async fn handshake(
+        acceptor: &TlsAcceptor,
+        connector: &TlsConnector,
+    ) -> (std::io::Result<ServerStream>, std::io::Result<ClientStream>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let name = server_name("localhost").unwrap();
+        tokio::join!(acceptor.accept(server), connector.connect(name, client))
+    }
+
+    /// Send `PING` over `stream` to a server running on `server`
+    async fn ping(server: ServerStream, client: ClientStream) -> Result<()> {
+        let task = tokio::spawn(server::process(Connection::new(server), Db::new(), std::future::pending()));
+        let mut client = Connection::new(client);
+        client.write_frame(&Frame::Array(vec![Frame::Bulk(Bytes::from("PING"))])).await.unwrap();
+        assert_eq!(client.read_frame().await.unwrap(), Some(Frame::Simple("PONG".to_string())));
+        // Hang up without `close_notify`, the server sees the stream end early
+        drop(client);
+        task.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let pki = Pki::generate("round-trip");
+        let (server, client) = handshake(&pki.acceptor(false), &pki.connector(false)).await;
+        let err = ping(server.unwrap(), client.unwrap()).await.unwrap_err();
+        assert!(server::is_unexpected_eof(&*err), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_accepts_a_client_certificate() {
+        let pki = Pki::generate("mtls-accept");
+        let (server, client) = handshake(&pki.acceptor(true), &pki.connector(true)).await;
+        let err = ping(server.unwrap(), client.unwrap()).await.unwrap_err();
+        assert!(server::is_unexpected_eof(&*err), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_rejects_a_client_without_certificate() {
+        let pki = Pki::generate("mtls-reject");
+        // With TLS 1.3 the client only learns it was rejected on its next read, the server knows right away
+        let (server, _) = handshake(&pki.acceptor(true), &pki.connector(false)).await;
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn unreadable_files() {
+        let pki = Pki::generate("files");
+        let missing = pki.path("missing.pem");
+        let err = acceptor(&missing, pki.path("server.key"), None).err().unwrap();
+        assert!(err.to_string().starts_with(&missing.display().to_string()), "{}", err);
+        // A key is not a certificate
+        assert!(acceptor(pki.path("server.key"), pki.path("server.key"), None).is_err());
+        assert!(connector(pki.path("server.key"), None).is_err());
+    }
+}